@@ -0,0 +1,1455 @@
+use std::time::Duration;
+
+use crate::{
+    config::ConfigKey,
+    message::{ExpiryOption, Message, ScoreComparison, SetCondition},
+    resp_value::RespValue,
+    store::{unix_time_millis, StoreExpiry},
+};
+
+/// A command the server understands, and how to parse a request for it.
+pub struct Command {
+    pub name: &'static str,
+    /// Whether the writes this command records are sent on to replicas. Admin commands
+    /// like `CONFIG SET` only affect the server they're run on.
+    pub propagate: bool,
+    /// Parse the request, given all of its elements (including the command name).
+    parse: fn(&[RespValue]) -> anyhow::Result<Message>,
+}
+
+/// Every command the server dispatches. This is the single source of truth for both
+/// parsing requests and `COMMAND COUNT`.
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: "PING",
+        propagate: false,
+        parse: |_| Ok(Message::Ping),
+    },
+    Command {
+        name: "ECHO",
+        propagate: false,
+        parse: |elements| Ok(Message::Echo(arg(elements, 1)?.to_string())),
+    },
+    Command {
+        name: "COMMAND",
+        propagate: false,
+        parse: parse_command,
+    },
+    Command {
+        name: "DEBUG",
+        propagate: false,
+        parse: parse_debug,
+    },
+    Command {
+        name: "LATENCY",
+        propagate: false,
+        parse: parse_latency,
+    },
+    Command {
+        name: "SLOWLOG",
+        propagate: false,
+        parse: parse_slowlog,
+    },
+    Command {
+        name: "HELLO",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::Hello {
+                protover: optional_int_arg(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "MONITOR",
+        propagate: false,
+        parse: |_| Ok(Message::Monitor),
+    },
+    Command {
+        name: "SET",
+        propagate: true,
+        parse: parse_set,
+    },
+    Command {
+        name: "GET",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::GetRequest {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "GETEX",
+        propagate: true,
+        parse: parse_getex,
+    },
+    Command {
+        name: "INCR",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::Incr {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "APPEND",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::Append {
+                key: arg(elements, 1)?.to_string(),
+                value: arg(elements, 2)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "LPUSH",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::LPush {
+                key: arg(elements, 1)?.to_string(),
+                values: args_from(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "RPUSH",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::RPush {
+                key: arg(elements, 1)?.to_string(),
+                values: args_from(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "LPOP",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::LPop {
+                key: arg(elements, 1)?.to_string(),
+                count: optional_int_arg(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "RPOP",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::RPop {
+                key: arg(elements, 1)?.to_string(),
+                count: optional_int_arg(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "LSET",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::LSet {
+                key: arg(elements, 1)?.to_string(),
+                index: int_arg(elements, 2)?,
+                value: arg(elements, 3)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "SADD",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::SAdd {
+                key: arg(elements, 1)?.to_string(),
+                members: args_from(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "SREM",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::SRem {
+                key: arg(elements, 1)?.to_string(),
+                members: args_from(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "SMEMBERS",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::SMembers {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "SCARD",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::SCard {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "SPOP",
+        // Propagated as an `SREM` of the members it chose, so replicas match
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::SPop {
+                key: arg(elements, 1)?.to_string(),
+                count: optional_int_arg(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "SRANDMEMBER",
+        propagate: false,
+        parse: |elements| {
+            let count = optional_int_arg::<i64>(elements, 2)?;
+            // Like Redis, a negative count has to leave room to be doubled
+            if count.is_some_and(|c| c < -(i64::MAX / 2)) {
+                return Err(anyhow::format_err!("value is out of range"));
+            }
+            Ok(Message::SRandMember {
+                key: arg(elements, 1)?.to_string(),
+                count,
+            })
+        },
+    },
+    Command {
+        name: "SISMEMBER",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::SIsMember {
+                key: arg(elements, 1)?.to_string(),
+                member: arg(elements, 2)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "HSET",
+        propagate: true,
+        parse: parse_hset,
+    },
+    Command {
+        name: "HDEL",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::HDel {
+                key: arg(elements, 1)?.to_string(),
+                fields: args_from(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "HMGET",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::HMGet {
+                key: arg(elements, 1)?.to_string(),
+                fields: args_from(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "HLEN",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::HLen {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "HEXISTS",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::HExists {
+                key: arg(elements, 1)?.to_string(),
+                field: arg(elements, 2)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "HGETALL",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::HGetAll {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "HKEYS",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::HKeys {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "HVALS",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::HVals {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "ZADD",
+        propagate: true,
+        parse: parse_zadd,
+    },
+    Command {
+        name: "ZRANGE",
+        propagate: false,
+        parse: |elements| {
+            let with_scores = match elements.len() {
+                4 => false,
+                5 if arg(elements, 4)?.eq_ignore_ascii_case("WITHSCORES") => true,
+                _ => return Err(anyhow::format_err!("syntax error")),
+            };
+            Ok(Message::ZRange {
+                key: arg(elements, 1)?.to_string(),
+                start: int_arg(elements, 2)?,
+                stop: int_arg(elements, 3)?,
+                with_scores,
+            })
+        },
+    },
+    Command {
+        name: "ZREM",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::ZRem {
+                key: arg(elements, 1)?.to_string(),
+                members: args_from(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "ZCARD",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::ZCard {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "ZINCRBY",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::ZIncrBy {
+                key: arg(elements, 1)?.to_string(),
+                amount: float_arg(elements, 2)?,
+                member: arg(elements, 3)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "ZRANK",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::ZRank {
+                key: arg(elements, 1)?.to_string(),
+                member: arg(elements, 2)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "ZREVRANK",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::ZRevRank {
+                key: arg(elements, 1)?.to_string(),
+                member: arg(elements, 2)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "SINTER",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::SInter {
+                keys: args_from(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "SUNION",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::SUnion {
+                keys: args_from(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "SDIFF",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::SDiff {
+                keys: args_from(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "SMISMEMBER",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::SMIsMember {
+                key: arg(elements, 1)?.to_string(),
+                members: args_from(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "EXISTS",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::Exists {
+                keys: args_from(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "FLUSHDB",
+        propagate: true,
+        parse: |elements| {
+            parse_flush_mode(elements)?;
+            Ok(Message::FlushDb)
+        },
+    },
+    Command {
+        name: "FLUSHALL",
+        propagate: true,
+        parse: |elements| {
+            parse_flush_mode(elements)?;
+            Ok(Message::FlushAll)
+        },
+    },
+    Command {
+        name: "SELECT",
+        // Propagated ahead of writes to a different database, rather than directly
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::Select {
+                index: int_arg(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "TOUCH",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::Touch {
+                keys: args_from(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "DEL",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::Del {
+                keys: args_from(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "UNLINK",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::Unlink {
+                keys: args_from(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "TYPE",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::Type {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "OBJECT",
+        propagate: false,
+        parse: parse_object,
+    },
+    Command {
+        name: "EXPIRE",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::Expire {
+                key: arg(elements, 1)?.to_string(),
+                seconds: int_arg(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "PEXPIRE",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::PExpire {
+                key: arg(elements, 1)?.to_string(),
+                millis: int_arg(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "EXPIREAT",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::ExpireAt {
+                key: arg(elements, 1)?.to_string(),
+                unix_seconds: int_arg(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "PEXPIREAT",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::PExpireAt {
+                key: arg(elements, 1)?.to_string(),
+                unix_millis: int_arg(elements, 2)?,
+            })
+        },
+    },
+    Command {
+        name: "PERSIST",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::Persist {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "RENAME",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::Rename {
+                src: arg(elements, 1)?.to_string(),
+                dst: arg(elements, 2)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "RENAMENX",
+        propagate: true,
+        parse: |elements| {
+            Ok(Message::RenameNx {
+                src: arg(elements, 1)?.to_string(),
+                dst: arg(elements, 2)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "COPY",
+        propagate: true,
+        parse: parse_copy,
+    },
+    Command {
+        name: "DUMP",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::Dump {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "RESTORE",
+        propagate: true,
+        parse: parse_restore,
+    },
+    Command {
+        name: "TTL",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::Ttl {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "PTTL",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::PTtl {
+                key: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "CLIENT",
+        propagate: false,
+        parse: parse_client,
+    },
+    Command {
+        name: "CONFIG",
+        propagate: false,
+        parse: parse_config,
+    },
+    Command {
+        name: "KEYS",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::KeysRequest {
+                pattern: arg(elements, 1)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "SCAN",
+        propagate: false,
+        parse: parse_scan,
+    },
+    Command {
+        name: "DBSIZE",
+        propagate: false,
+        parse: |_| Ok(Message::DbSize),
+    },
+    Command {
+        name: "RANDOMKEY",
+        propagate: false,
+        parse: |_| Ok(Message::RandomKey),
+    },
+    Command {
+        name: "INFO",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::InfoRequest {
+                sections: optional_args_from(elements, 1)?,
+            })
+        },
+    },
+    Command {
+        name: "REPLCONF",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::ReplicationConfig {
+                key: arg(elements, 1)?.to_string(),
+                value: arg(elements, 2)?.to_string(),
+            })
+        },
+    },
+    Command {
+        name: "PSYNC",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::PSync {
+                replication_id: arg(elements, 1)?.to_string(),
+                offset: arg(elements, 2)?.parse::<isize>()?,
+            })
+        },
+    },
+    Command {
+        name: "WAIT",
+        propagate: false,
+        parse: |elements| {
+            Ok(Message::Wait {
+                num_replicas: int_arg(elements, 1)?,
+                timeout: Duration::from_millis(int_arg(elements, 2)?),
+            })
+        },
+    },
+];
+
+impl Command {
+    /// Find the command with the given name, ignoring case.
+    pub fn lookup(name: &str) -> Option<&'static Command> {
+        COMMANDS.iter().find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The command a request message is sent as, if it's a request at all.
+    pub fn of(message: &Message) -> Option<&'static Command> {
+        Command::lookup(message.name()?)
+    }
+
+    pub fn parse(&self, elements: &[RespValue]) -> anyhow::Result<Message> {
+        (self.parse)(elements)
+    }
+}
+
+fn malformed(elements: &[RespValue]) -> anyhow::Error {
+    match elements.first() {
+        Some(RespValue::BulkString(name)) => {
+            anyhow::format_err!("malformed {} command", name.to_ascii_uppercase())
+        }
+        _ => anyhow::format_err!("malformed command"),
+    }
+}
+
+/// The bulk string argument at `index`.
+fn arg<'a>(elements: &[RespValue<'a>], index: usize) -> anyhow::Result<&'a str> {
+    match elements.get(index) {
+        Some(RespValue::BulkString(s)) => Ok(s),
+        _ => Err(malformed(elements)),
+    }
+}
+
+/// The integer argument at `index`. Like Redis, any integer argument must fit in an
+/// `i64`, as well as in `T`, and it's the same error either way.
+fn int_arg<T: TryFrom<i64>>(elements: &[RespValue], index: usize) -> anyhow::Result<T> {
+    arg(elements, index)?
+        .parse::<i64>()
+        .ok()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| anyhow::format_err!("value is not an integer or out of range"))
+}
+
+/// The float argument at `index`, which can be infinite but not NaN.
+fn float_arg(elements: &[RespValue], index: usize) -> anyhow::Result<f64> {
+    arg(elements, index)?
+        .parse::<f64>()
+        .ok()
+        .filter(|f| !f.is_nan())
+        .ok_or_else(|| anyhow::format_err!("value is not a valid float"))
+}
+
+/// All arguments from `index` onwards, of which there must be at least one.
+fn args_from(elements: &[RespValue], index: usize) -> anyhow::Result<Vec<String>> {
+    let args = optional_args_from(elements, index)?;
+    if args.is_empty() {
+        return Err(malformed(elements));
+    }
+    Ok(args)
+}
+
+/// The integer argument at `index`, if there is one.
+fn optional_int_arg<T: TryFrom<i64>>(
+    elements: &[RespValue],
+    index: usize,
+) -> anyhow::Result<Option<T>> {
+    if index < elements.len() {
+        int_arg(elements, index).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// All arguments from `index` onwards, if any.
+fn optional_args_from(elements: &[RespValue], index: usize) -> anyhow::Result<Vec<String>> {
+    (index..elements.len())
+        .map(|i| arg(elements, i).map(|s| s.to_string()))
+        .collect()
+}
+
+fn parse_command(elements: &[RespValue]) -> anyhow::Result<Message> {
+    match arg(elements, 1)?.to_ascii_uppercase().as_str() {
+        "DOCS" => Ok(Message::CommandDocs),
+        "COUNT" => Ok(Message::CommandCount),
+        subcommand => Err(anyhow::format_err!(
+            "unhandled COMMAND subcommand {:?}",
+            subcommand
+        )),
+    }
+}
+
+fn parse_object(elements: &[RespValue]) -> anyhow::Result<Message> {
+    match arg(elements, 1)?.to_ascii_uppercase().as_str() {
+        "ENCODING" => Ok(Message::ObjectEncoding {
+            key: arg(elements, 2)?.to_string(),
+        }),
+        subcommand => Err(anyhow::format_err!(
+            "unhandled OBJECT subcommand {:?}",
+            subcommand
+        )),
+    }
+}
+
+fn parse_client(elements: &[RespValue]) -> anyhow::Result<Message> {
+    match arg(elements, 1)?.to_ascii_uppercase().as_str() {
+        "PAUSE" => {
+            let timeout = Duration::from_millis(int_arg(elements, 2)?);
+            let writes_only = match elements.get(3) {
+                None => false,
+                Some(_) => match arg(elements, 3)?.to_ascii_uppercase().as_str() {
+                    "WRITE" => true,
+                    "ALL" => false,
+                    _ => return Err(anyhow::format_err!("syntax error")),
+                },
+            };
+            if elements.len() > 4 {
+                return Err(anyhow::format_err!("syntax error"));
+            }
+            Ok(Message::ClientPause {
+                timeout,
+                writes_only,
+            })
+        }
+        "UNPAUSE" => Ok(Message::ClientUnpause),
+        subcommand => Err(anyhow::format_err!(
+            "unhandled CLIENT subcommand {:?}",
+            subcommand
+        )),
+    }
+}
+
+fn parse_debug(elements: &[RespValue]) -> anyhow::Result<Message> {
+    match arg(elements, 1)?.to_ascii_uppercase().as_str() {
+        "SLEEP" => {
+            let seconds = arg(elements, 2)?
+                .parse::<f64>()
+                .map_err(|_| anyhow::format_err!("value is not a valid float"))?;
+            let duration = Duration::try_from_secs_f64(seconds)
+                .map_err(|_| anyhow::format_err!("value is out of range"))?;
+            Ok(Message::DebugSleep(duration))
+        }
+        "RELOAD" => Ok(Message::DebugReload),
+        subcommand => Err(anyhow::format_err!(
+            "unhandled DEBUG subcommand {:?}",
+            subcommand
+        )),
+    }
+}
+
+fn parse_latency(elements: &[RespValue]) -> anyhow::Result<Message> {
+    match arg(elements, 1)?.to_ascii_uppercase().as_str() {
+        "LATEST" => Ok(Message::LatencyLatest),
+        "HISTORY" => Ok(Message::LatencyHistory {
+            event: arg(elements, 2)?.to_string(),
+        }),
+        "RESET" => Ok(Message::LatencyReset {
+            events: optional_args_from(elements, 2)?,
+        }),
+        subcommand => Err(anyhow::format_err!(
+            "unhandled LATENCY subcommand {:?}",
+            subcommand
+        )),
+    }
+}
+
+fn parse_slowlog(elements: &[RespValue]) -> anyhow::Result<Message> {
+    match arg(elements, 1)?.to_ascii_uppercase().as_str() {
+        "GET" => {
+            let count = match optional_int_arg::<i64>(elements, 2)? {
+                None => Some(10),
+                Some(-1) => None,
+                Some(count) => Some(usize::try_from(count).map_err(|_| {
+                    anyhow::format_err!("count should be greater than or equal to -1")
+                })?),
+            };
+            Ok(Message::SlowLogGet { count })
+        }
+        "LEN" => Ok(Message::SlowLogLen),
+        "RESET" => Ok(Message::SlowLogReset),
+        subcommand => Err(anyhow::format_err!(
+            "unhandled SLOWLOG subcommand {:?}",
+            subcommand
+        )),
+    }
+}
+
+fn parse_config(elements: &[RespValue]) -> anyhow::Result<Message> {
+    match arg(elements, 1)?.to_ascii_uppercase().as_str() {
+        "GET" => {
+            let key = arg(elements, 2)?;
+            match ConfigKey::deserialize(key) {
+                Ok(key) => Ok(Message::ConfigGetRequest { key }),
+                Err(_) => Err(anyhow::format_err!("invalid config key {:?}", key)),
+            }
+        }
+        "SET" => {
+            let key = arg(elements, 2)?;
+            match ConfigKey::deserialize(key) {
+                Ok(ConfigKey::Unknown) | Err(_) => {
+                    Err(anyhow::format_err!("invalid config key {:?}", key))
+                }
+                Ok(key) => {
+                    let values = args_from(elements, 3)?;
+                    if values.len() != key.value_count() {
+                        return Err(malformed(elements));
+                    }
+                    Ok(Message::ConfigSetRequest { key, values })
+                }
+            }
+        }
+        command => Err(anyhow::format_err!(
+            "unhandled CONFIG command {:?}",
+            command
+        )),
+    }
+}
+
+fn parse_set(elements: &[RespValue]) -> anyhow::Result<Message> {
+    let key = arg(elements, 1)?;
+    let value = arg(elements, 2)?;
+    let mut expiry = None;
+    let mut keep_ttl = false;
+    let mut condition = None;
+    let mut return_old = false;
+    let mut i = 3;
+    while i < elements.len() {
+        let option = arg(elements, i)?.to_ascii_uppercase();
+        if option == "KEEPTTL" {
+            keep_ttl = true;
+        } else if option == "GET" {
+            return_old = true;
+        } else if option == "NX" || option == "XX" {
+            if condition.is_some() {
+                return Err(anyhow::format_err!("syntax error"));
+            }
+            condition = Some(if option == "NX" {
+                SetCondition::NotExists
+            } else {
+                SetCondition::Exists
+            });
+        } else {
+            if expiry.is_some() {
+                return Err(anyhow::format_err!("syntax error"));
+            }
+            i += 1;
+            expiry = Some(parse_expiry("set", &option, elements.get(i))?);
+        }
+        if keep_ttl && expiry.is_some() {
+            return Err(anyhow::format_err!("syntax error"));
+        }
+        i += 1;
+    }
+    Ok(Message::Set {
+        key: key.to_string(),
+        value: value.to_string(),
+        expiry,
+        keep_ttl,
+        condition,
+        return_old,
+    })
+}
+
+fn parse_copy(elements: &[RespValue]) -> anyhow::Result<Message> {
+    let src = arg(elements, 1)?;
+    let dst = arg(elements, 2)?;
+    let mut db = None;
+    let mut replace = false;
+    let mut i = 3;
+    while i < elements.len() {
+        let option = arg(elements, i)?.to_ascii_uppercase();
+        if option == "REPLACE" {
+            replace = true;
+        } else if option == "DB" && i + 1 < elements.len() {
+            i += 1;
+            db = Some(int_arg(elements, i)?);
+        } else {
+            return Err(anyhow::format_err!("syntax error"));
+        }
+        i += 1;
+    }
+    Ok(Message::Copy {
+        src: src.to_string(),
+        dst: dst.to_string(),
+        db,
+        replace,
+    })
+}
+
+fn parse_restore(elements: &[RespValue]) -> anyhow::Result<Message> {
+    let key = arg(elements, 1)?;
+    let ttl = int_arg::<i64>(elements, 2)?;
+    let payload = arg(elements, 3)?;
+    let mut replace = false;
+    let mut absolute_ttl = false;
+    for i in 4..elements.len() {
+        match arg(elements, i)?.to_ascii_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absolute_ttl = true,
+            _ => return Err(anyhow::format_err!("syntax error")),
+        }
+    }
+    Ok(Message::Restore {
+        key: key.to_string(),
+        ttl: u64::try_from(ttl)
+            .map_err(|_| anyhow::format_err!("Invalid TTL value, must be >= 0"))?,
+        payload: payload.to_string(),
+        replace,
+        absolute_ttl,
+    })
+}
+
+/// Check the optional `ASYNC`/`SYNC` argument to `FLUSHDB` and `FLUSHALL`. Flushing
+/// is always synchronous, so it's otherwise ignored.
+fn parse_flush_mode(elements: &[RespValue]) -> anyhow::Result<()> {
+    match elements.len() {
+        1 => Ok(()),
+        2 if ["ASYNC", "SYNC"]
+            .iter()
+            .any(|mode| arg(elements, 1).is_ok_and(|a| a.eq_ignore_ascii_case(mode))) =>
+        {
+            Ok(())
+        }
+        _ => Err(anyhow::format_err!("syntax error")),
+    }
+}
+
+fn parse_hset(elements: &[RespValue]) -> anyhow::Result<Message> {
+    let key = arg(elements, 1)?;
+    if elements.len() < 4 || elements.len() % 2 == 1 {
+        return Err(malformed(elements));
+    }
+    let fields = (2..elements.len())
+        .step_by(2)
+        .map(|i| {
+            Ok((
+                arg(elements, i)?.to_string(),
+                arg(elements, i + 1)?.to_string(),
+            ))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Message::HSet {
+        key: key.to_string(),
+        fields,
+    })
+}
+
+fn parse_zadd(elements: &[RespValue]) -> anyhow::Result<Message> {
+    let key = arg(elements, 1)?;
+    let mut condition = None;
+    let mut comparison = None;
+    let mut changed = false;
+    let mut i = 2;
+    while let Ok(option) = arg(elements, i) {
+        match option.to_ascii_uppercase().as_str() {
+            "NX" | "XX" => {
+                let option = if option.eq_ignore_ascii_case("NX") {
+                    SetCondition::NotExists
+                } else {
+                    SetCondition::Exists
+                };
+                if condition.is_some_and(|c| c != option) {
+                    return Err(anyhow::format_err!(
+                        "XX and NX options at the same time are not compatible"
+                    ));
+                }
+                condition = Some(option);
+            }
+            "GT" | "LT" => {
+                let option = if option.eq_ignore_ascii_case("GT") {
+                    ScoreComparison::Greater
+                } else {
+                    ScoreComparison::Less
+                };
+                if comparison.is_some_and(|c| c != option) {
+                    return Err(anyhow::format_err!(
+                        "GT, LT, and/or NX options at the same time are not compatible"
+                    ));
+                }
+                comparison = Some(option);
+            }
+            "CH" => changed = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    if comparison.is_some() && condition == Some(SetCondition::NotExists) {
+        return Err(anyhow::format_err!(
+            "GT, LT, and/or NX options at the same time are not compatible"
+        ));
+    }
+
+    let pairs = &elements[i..];
+    if pairs.is_empty() || pairs.len() % 2 == 1 {
+        return Err(anyhow::format_err!("syntax error"));
+    }
+    let members = (i..elements.len())
+        .step_by(2)
+        .map(|i| Ok((float_arg(elements, i)?, arg(elements, i + 1)?.to_string())))
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Message::ZAdd {
+        key: key.to_string(),
+        condition,
+        comparison,
+        changed,
+        members,
+    })
+}
+
+fn parse_scan(elements: &[RespValue]) -> anyhow::Result<Message> {
+    let cursor = arg(elements, 1)?
+        .parse::<u64>()
+        .map_err(|_| anyhow::format_err!("invalid cursor"))?;
+    let mut pattern = None;
+    let mut count = None;
+    let mut type_name = None;
+    let mut i = 2;
+    while i < elements.len() {
+        let option = arg(elements, i)?.to_ascii_uppercase();
+        let value = arg(elements, i + 1).map_err(|_| anyhow::format_err!("syntax error"))?;
+        match option.as_str() {
+            "MATCH" => pattern = Some(value.to_string()),
+            "TYPE" => type_name = Some(value.to_string()),
+            "COUNT" => {
+                let n = int_arg::<i64>(elements, i + 1)?;
+                if n < 1 {
+                    return Err(anyhow::format_err!("syntax error"));
+                }
+                count = Some(n as usize);
+            }
+            _ => return Err(anyhow::format_err!("syntax error")),
+        }
+        i += 2;
+    }
+    Ok(Message::Scan {
+        cursor,
+        pattern,
+        count,
+        type_name,
+    })
+}
+
+fn parse_getex(elements: &[RespValue]) -> anyhow::Result<Message> {
+    let key = arg(elements, 1)?;
+    let expiry_option = match elements.get(2) {
+        Some(RespValue::BulkString(s)) if s.eq_ignore_ascii_case("PERSIST") => {
+            Some(ExpiryOption::Persist)
+        }
+        Some(RespValue::BulkString(s)) => Some(ExpiryOption::Set(parse_expiry(
+            "getex",
+            &s.to_ascii_uppercase(),
+            elements.get(3),
+        )?)),
+        None => None,
+        _ => return Err(malformed(elements)),
+    };
+    let expected_len = match expiry_option {
+        Some(ExpiryOption::Set(_)) => 4,
+        Some(ExpiryOption::Persist) => 3,
+        None => 2,
+    };
+    if elements.len() != expected_len {
+        return Err(anyhow::format_err!("syntax error"));
+    }
+    Ok(Message::GetEx {
+        key: key.to_string(),
+        expiry_option,
+    })
+}
+
+/// Parse an `EX`/`PX`/`EXAT`/`PXAT` option (already uppercased) and its argument.
+fn parse_expiry(
+    command: &str,
+    option: &str,
+    value: Option<&RespValue>,
+) -> anyhow::Result<StoreExpiry> {
+    let n = match value {
+        Some(RespValue::BulkString(s)) => s
+            .parse::<i64>()
+            .map_err(|_| anyhow::format_err!("value is not an integer or out of range"))?,
+        _ => return Err(anyhow::format_err!("syntax error")),
+    };
+    let invalid = || anyhow::format_err!("invalid expire time in '{}' command", command);
+    if n <= 0 {
+        return Err(invalid());
+    }
+    let millis = match option {
+        "EX" | "EXAT" => n.checked_mul(1000).ok_or_else(invalid)?,
+        "PX" | "PXAT" => n,
+        _ => return Err(anyhow::format_err!("syntax error")),
+    };
+    match option {
+        "EX" | "PX" => {
+            // The expiry has to be representable as a unix time once it's made absolute
+            (unix_time_millis() as i64)
+                .checked_add(millis)
+                .ok_or_else(invalid)?;
+            Ok(StoreExpiry::Duration(Duration::from_millis(millis as u64)))
+        }
+        _ => Ok(StoreExpiry::UnixTimestampMillis(millis as u64)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, COMMANDS};
+    use crate::{
+        message::{Message, ScoreComparison, SetCondition},
+        resp_value::RespValue,
+        store::StoreExpiry,
+    };
+    use bytes::BytesMut;
+    use std::time::Duration;
+
+    fn command_data(args: &[&str]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        RespValue::Array(args.iter().map(|a| RespValue::BulkString(a)).collect())
+            .serialize(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn set_expiry_options() {
+        {
+            let data = command_data(&["SET", "foo", "bar", "EX", "10"]);
+            let (message, _) = Message::deserialize(&data).unwrap();
+            assert!(matches!(
+                message,
+                Message::Set {
+                    expiry: Some(StoreExpiry::Duration(d)),
+                    keep_ttl: false,
+                    ..
+                } if d == Duration::from_secs(10)
+            ));
+        }
+
+        {
+            let data = command_data(&["SET", "foo", "bar", "exat", "1700000000"]);
+            let (message, _) = Message::deserialize(&data).unwrap();
+            assert!(matches!(
+                message,
+                Message::Set {
+                    expiry: Some(StoreExpiry::UnixTimestampMillis(1700000000000)),
+                    ..
+                }
+            ));
+        }
+
+        {
+            let data = command_data(&["SET", "foo", "bar", "PXAT", "1700000000123"]);
+            let (message, _) = Message::deserialize(&data).unwrap();
+            assert!(matches!(
+                message,
+                Message::Set {
+                    expiry: Some(StoreExpiry::UnixTimestampMillis(1700000000123)),
+                    ..
+                }
+            ));
+        }
+
+        {
+            let data = command_data(&["SET", "foo", "bar", "KEEPTTL"]);
+            let (message, _) = Message::deserialize(&data).unwrap();
+            assert!(matches!(
+                message,
+                Message::Set {
+                    expiry: None,
+                    keep_ttl: true,
+                    ..
+                }
+            ));
+        }
+
+        {
+            // Conflicting options
+            let data = command_data(&["SET", "foo", "bar", "EX", "10", "KEEPTTL"]);
+            assert!(Message::deserialize(&data).is_err());
+            let data = command_data(&["SET", "foo", "bar", "EX", "10", "PX", "100"]);
+            assert!(Message::deserialize(&data).is_err());
+        }
+
+        {
+            // Non-numeric expiry
+            let data = command_data(&["SET", "foo", "bar", "PX", "soon"]);
+            assert!(Message::deserialize(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn set_condition_options() {
+        {
+            let data = command_data(&["SET", "foo", "bar", "NX", "GET"]);
+            let (message, _) = Message::deserialize(&data).unwrap();
+            assert!(matches!(
+                message,
+                Message::Set {
+                    condition: Some(SetCondition::NotExists),
+                    return_old: true,
+                    ..
+                }
+            ));
+        }
+
+        {
+            let data = command_data(&["SET", "foo", "bar", "xx"]);
+            let (message, _) = Message::deserialize(&data).unwrap();
+            assert!(matches!(
+                message,
+                Message::Set {
+                    condition: Some(SetCondition::Exists),
+                    return_old: false,
+                    ..
+                }
+            ));
+        }
+
+        {
+            // Conflicting conditions
+            let data = command_data(&["SET", "foo", "bar", "NX", "XX"]);
+            assert!(Message::deserialize(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn registry() {
+        for command in COMMANDS {
+            assert!(std::ptr::eq(
+                Command::lookup(&command.name.to_ascii_lowercase()).unwrap(),
+                command
+            ));
+
+            // Every registered command is dispatched, even if it's missing arguments
+            if let Err(e) = Message::deserialize(&command_data(&[command.name])) {
+                assert!(!e.to_string().starts_with("unknown command"));
+            }
+        }
+
+        assert!(Message::deserialize(&command_data(&["NOTACOMMAND"])).is_err());
+    }
+
+    #[test]
+    fn command_of() {
+        for args in [
+            &["SET", "foo", "bar", "PX", "100"][..],
+            &["RPOP", "list"],
+            &["HVALS", "hash"],
+            &["ZREVRANK", "zset", "a"],
+            &["SDIFF", "a", "b"],
+            &["CONFIG", "GET", "dir"],
+            &["DEBUG", "RELOAD"],
+            &["RESTORE", "key", "0", "payload"],
+        ] {
+            let (message, _) = Message::deserialize(&command_data(args)).unwrap();
+            assert_eq!(message.name(), Some(args[0]));
+            assert_eq!(Command::of(&message).unwrap().name, args[0]);
+        }
+
+        // Replies aren't commands, even when their contents look like one
+        let reply = Message::KeysResponse {
+            keys: vec!["SET".to_string()],
+        };
+        assert!(Command::of(&reply).is_none());
+    }
+
+    #[test]
+    fn protocol_error() {
+        let error = Message::deserialize(b"*1\r\n:5\r\n").unwrap_err();
+        assert_eq!(error.to_string(), "Protocol error: expected '$', got ':'");
+
+        let error = Message::deserialize(b"*2\r\n$4\r\nECHO\r\n*0\r\n").unwrap_err();
+        assert_eq!(error.to_string(), "Protocol error: expected '$', got '*'");
+    }
+
+    #[test]
+    fn invalid_expire_time() {
+        for args in [
+            &["SET", "foo", "bar", "PX", "0"][..],
+            &["SET", "foo", "bar", "EX", "-5"],
+            &["SET", "foo", "bar", "EX", "9223372036854775807"],
+            &["SET", "foo", "bar", "PX", "9223372036854775807"],
+            &["SET", "foo", "bar", "EXAT", "9223372036854775807"],
+            &["GETEX", "foo", "PX", "0"],
+        ] {
+            let data = command_data(args);
+            let error = Message::deserialize(&data).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "invalid expire time in '{}' command",
+                    args[0].to_lowercase()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn srandmember_count_out_of_range() {
+        let data = command_data(&["SRANDMEMBER", "set", "-9223372036854775808"]);
+        assert_eq!(
+            Message::deserialize(&data).unwrap_err().to_string(),
+            "value is out of range"
+        );
+        let data = command_data(&["SRANDMEMBER", "set", "-4611686018427387903"]);
+        assert!(Message::deserialize(&data).is_ok());
+    }
+
+    #[test]
+    fn debug_sleep() {
+        let data = command_data(&["DEBUG", "SLEEP", "0.5"]);
+        let (message, _) = Message::deserialize(&data).unwrap();
+        assert!(matches!(message, Message::DebugSleep(d) if d == Duration::from_millis(500)));
+
+        for (seconds, error) in [
+            ("abc", "value is not a valid float"),
+            ("-1", "value is out of range"),
+            ("1e20", "value is out of range"),
+            ("inf", "value is out of range"),
+        ] {
+            let data = command_data(&["DEBUG", "SLEEP", seconds]);
+            assert_eq!(Message::deserialize(&data).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn zadd_options() {
+        let data = command_data(&["ZADD", "zset", "GT", "CH", "5", "a", "-inf", "b"]);
+        let (message, _) = Message::deserialize(&data).unwrap();
+        let Message::ZAdd {
+            condition: None,
+            comparison: Some(ScoreComparison::Greater),
+            changed: true,
+            members,
+            ..
+        } = message
+        else {
+            panic!("unexpected message {:?}", message);
+        };
+        assert_eq!(
+            members,
+            [(5.0, "a".to_string()), (f64::NEG_INFINITY, "b".to_string())]
+        );
+
+        for args in [
+            &["ZADD", "zset", "NX", "GT", "5", "a"][..],
+            &["ZADD", "zset", "LT", "NX", "5", "a"],
+            &["ZADD", "zset", "GT", "LT", "5", "a"],
+        ] {
+            let error = Message::deserialize(&command_data(args)).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "GT, LT, and/or NX options at the same time are not compatible"
+            );
+        }
+        for (args, expected) in [
+            (
+                &["ZADD", "zset", "NX", "XX", "5", "a"][..],
+                "XX and NX options at the same time are not compatible",
+            ),
+            (&["ZADD", "zset", "5"], "syntax error"),
+            (&["ZADD", "zset", "5", "a", "6"], "syntax error"),
+            (&["ZADD", "zset", "five", "a"], "value is not a valid float"),
+            (&["ZADD", "zset", "nan", "a"], "value is not a valid float"),
+        ] {
+            let error = Message::deserialize(&command_data(args)).unwrap_err();
+            assert_eq!(error.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn integer_arguments() {
+        let too_big = (i64::MAX as u64 + 1).to_string();
+        for args in [
+            &["LPOP", "list", too_big.as_str()][..],
+            &["LPOP", "list", "-1"],
+            &["LPOP", "list", "one"],
+            &["LSET", "list", too_big.as_str(), "x"],
+            &["SELECT", too_big.as_str()],
+            &["EXPIRE", "foo", too_big.as_str()],
+            &["WAIT", too_big.as_str(), "0"],
+            &["WAIT", "1", "soon"],
+        ] {
+            let error = Message::deserialize(&command_data(args)).unwrap_err();
+            assert_eq!(error.to_string(), "value is not an integer or out of range");
+        }
+
+        let data = command_data(&["LPOP", "list", &i64::MAX.to_string()]);
+        let (message, _) = Message::deserialize(&data).unwrap();
+        assert!(matches!(message, Message::LPop { count: Some(n), .. } if n == i64::MAX as usize));
+    }
+}
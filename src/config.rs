@@ -35,6 +35,11 @@ pub enum ConfigKey {
     DbFilename,
     Port,
     ReplicaOf,
+    Databases,
+    LatencyMonitorThreshold,
+    SlowlogLogSlowerThan,
+    SlowlogMaxLen,
+    ProtoMaxBulkLen,
     Unknown,
 }
 
@@ -45,6 +50,11 @@ impl ConfigKey {
             "dbfilename" => Ok(ConfigKey::DbFilename),
             "port" => Ok(ConfigKey::Port),
             "replicaof" => Ok(ConfigKey::ReplicaOf),
+            "databases" => Ok(ConfigKey::Databases),
+            "latency-monitor-threshold" => Ok(ConfigKey::LatencyMonitorThreshold),
+            "slowlog-log-slower-than" => Ok(ConfigKey::SlowlogLogSlowerThan),
+            "slowlog-max-len" => Ok(ConfigKey::SlowlogMaxLen),
+            "proto-max-bulk-len" => Ok(ConfigKey::ProtoMaxBulkLen),
             _ => Ok(ConfigKey::Unknown),
         }
     }
@@ -55,6 +65,11 @@ impl ConfigKey {
             ConfigKey::DbFilename => "dbfilename",
             ConfigKey::Port => "port",
             ConfigKey::ReplicaOf => "replicaof",
+            ConfigKey::Databases => "databases",
+            ConfigKey::LatencyMonitorThreshold => "latency-monitor-threshold",
+            ConfigKey::SlowlogLogSlowerThan => "slowlog-log-slower-than",
+            ConfigKey::SlowlogMaxLen => "slowlog-max-len",
+            ConfigKey::ProtoMaxBulkLen => "proto-max-bulk-len",
             ConfigKey::Unknown => unreachable!(),
         }
     }
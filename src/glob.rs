@@ -0,0 +1,131 @@
+/// Match a string against a Redis glob-style pattern, supporting `*`, `?`,
+/// `[...]` character classes (with `^` negation and `a-z` ranges), and `\` escapes.
+pub fn glob_match(pattern: &str, string: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let string = string.as_bytes();
+
+    let mut p = 0;
+    let mut s = 0;
+    // Where to resume from if the current attempt fails: the pattern index just
+    // after the last `*`, and the string index it should next try to consume from
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p + 1, s));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match match_class(pattern, p + 1, string[s]) {
+                (true, next) => Some(next),
+                (false, _) => None,
+            },
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == string[s]).then_some(p + 2),
+            Some(c) => (*c == string[s]).then_some(p + 1),
+            None => None,
+        };
+
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                s += 1;
+            }
+            (None, Some((star_p, star_s))) => {
+                // Let the last `*` swallow one more character and try again
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, star_s + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Match `c` against the character class starting at `start` (just after the `[`),
+/// returning whether it matched and the pattern index after the closing `]`.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> (bool, usize) {
+    let mut i = start;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (low, high) = if pattern[i] <= pattern[i + 2] {
+                (pattern[i], pattern[i + 2])
+            } else {
+                (pattern[i + 2], pattern[i])
+            };
+            matched |= low <= c && c <= high;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+
+    // An unterminated class runs to the end of the pattern
+    (matched != negate, (i + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn literal() {
+        assert!(glob_match("hello", "hello"));
+        assert!(!glob_match("hello", "hell"));
+        assert!(!glob_match("hello", "helloo"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(!glob_match("h?llo", "heello"));
+    }
+
+    #[test]
+    fn star() {
+        assert!(glob_match("h*llo", "hllo"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(glob_match("h*llo", "hellollo"));
+        assert!(!glob_match("h*llo", "hellx"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("a*b*c", "aXXbYY"));
+    }
+
+    #[test]
+    fn character_class() {
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-b]llo", "hbllo"));
+        assert!(!glob_match("h[a-b]llo", "hcllo"));
+    }
+
+    #[test]
+    fn escape() {
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+        assert!(glob_match("h\\?llo", "h?llo"));
+        assert!(!glob_match("h\\?llo", "hello"));
+    }
+}
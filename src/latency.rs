@@ -0,0 +1,112 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::store::unix_time_millis;
+
+/// How many samples are kept for each event, like Redis.
+const HISTORY_LEN: usize = 160;
+
+/// Latency spikes for `LATENCY`, recorded for each event that took at least the
+/// `latency-monitor-threshold`.
+#[derive(Default)]
+pub struct LatencyMonitor {
+    events: HashMap<&'static str, LatencyEvent>,
+}
+
+#[derive(Default)]
+struct LatencyEvent {
+    /// Recent spikes as (unix seconds, milliseconds), oldest first. At most one
+    /// sample is kept per second, the worst of them.
+    samples: VecDeque<(u64, u64)>,
+    max_millis: u64,
+}
+
+/// The most recent spike for an event, as reported by `LATENCY LATEST`.
+pub struct LatestSpike {
+    pub event: &'static str,
+    pub unix_seconds: u64,
+    pub millis: u64,
+    pub max_millis: u64,
+}
+
+impl LatencyMonitor {
+    pub fn record(&mut self, event: &'static str, millis: u64) {
+        let now = unix_time_millis() / 1000;
+        let entry = self.events.entry(event).or_default();
+        entry.max_millis = entry.max_millis.max(millis);
+        match entry.samples.back_mut() {
+            Some((time, latency)) if *time == now => *latency = (*latency).max(millis),
+            _ => {
+                if entry.samples.len() == HISTORY_LEN {
+                    entry.samples.pop_front();
+                }
+                entry.samples.push_back((now, millis));
+            }
+        }
+    }
+
+    pub fn latest(&self) -> Vec<LatestSpike> {
+        let mut latest = self
+            .events
+            .iter()
+            .filter_map(|(event, entry)| {
+                let (unix_seconds, millis) = *entry.samples.back()?;
+                Some(LatestSpike {
+                    event,
+                    unix_seconds,
+                    millis,
+                    max_millis: entry.max_millis,
+                })
+            })
+            .collect::<Vec<_>>();
+        latest.sort_by_key(|spike| spike.event);
+        latest
+    }
+
+    /// Every recorded (unix seconds, milliseconds) sample for `event`, oldest first.
+    pub fn history(&self, event: &str) -> Vec<(u64, u64)> {
+        self.events
+            .get(event)
+            .map(|entry| entry.samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget `events`, or every event if none are given, returning how many were
+    /// forgotten.
+    pub fn reset(&mut self, events: &[String]) -> usize {
+        let before = self.events.len();
+        if events.is_empty() {
+            self.events.clear();
+        } else {
+            self.events
+                .retain(|event, _| !events.iter().any(|e| e.eq_ignore_ascii_case(event)));
+        }
+        before - self.events.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LatencyMonitor;
+
+    #[test]
+    fn record() {
+        let mut monitor = LatencyMonitor::default();
+        assert!(monitor.latest().is_empty());
+
+        // Samples in the same second are merged, keeping the worst
+        monitor.record("command", 150);
+        monitor.record("command", 120);
+        let history = monitor.history("command");
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].1, 150);
+
+        let latest = monitor.latest();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].event, "command");
+        assert_eq!(latest[0].max_millis, 150);
+
+        assert_eq!(monitor.reset(&["other".to_string()]), 0);
+        assert_eq!(monitor.reset(&[]), 1);
+        assert!(monitor.history("command").is_empty());
+    }
+}
@@ -1,39 +1,57 @@
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use message::Message;
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    collections::HashMap,
+    future::Future,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::Arc,
     time::Duration,
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
     sync::{
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
         Mutex,
     },
-    time::timeout,
+    time::{sleep, timeout},
 };
 
 use config::{Config, ConfigKey};
-use resp_value::RespValue;
-use state::State;
+use resp_value::{Incomplete, Protocol, RespValue};
+use state::{PendingWait, State};
 
+mod command;
 mod config;
+mod glob;
+mod latency;
 mod message;
+mod random;
 mod rdb;
 mod resp_value;
+mod slowlog;
 mod state;
 mod store;
 
 const ADDRESS: Ipv4Addr = Ipv4Addr::LOCALHOST;
 const DEFAULT_PORT: u16 = 6379;
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(10);
 const REPLICATION_ID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
 
 #[derive(Debug)]
 pub struct Connection {
     pub ty: ConnectionType,
     pub send_rdb: bool,
+    pub replica_id: Option<usize>,
+    /// Index of the database selected with `SELECT`.
+    pub db: usize,
+    /// A `WAIT` still waiting on replicas to acknowledge.
+    pub pending_wait: Option<PendingWait>,
+    pub addr: Option<SocketAddr>,
+    /// Set once the connection has sent `MONITOR`.
+    pub monitor_id: Option<usize>,
+    pub protocol: Protocol,
 }
 
 #[derive(Debug)]
@@ -46,10 +64,13 @@ pub enum ConnectionType {
 async fn handle_connection(
     mut stream: TcpStream,
     state: Arc<Mutex<State>>,
-    replica_senders: Arc<Mutex<Vec<UnboundedSender<Message>>>>,
+    replica_senders: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>>,
     connection_type: ConnectionType,
 ) {
     let mut input_buf = [0; 512];
+    // Everything read that hasn't been handled yet, which may end partway through
+    // a frame
+    let mut input = BytesMut::with_capacity(512);
     let mut output_buf = BytesMut::with_capacity(512);
 
     let mut reciever: Option<UnboundedReceiver<Message>> = None;
@@ -57,12 +78,18 @@ async fn handle_connection(
     let mut connection = Connection {
         ty: connection_type,
         send_rdb: false,
+        replica_id: None,
+        db: 0,
+        pending_wait: None,
+        addr: stream.peer_addr().ok(),
+        monitor_id: None,
+        protocol: Protocol::Resp2,
     };
 
     loop {
         if let Some(message) = state.lock().await.next_outgoing(&mut connection).unwrap() {
             output_buf.clear();
-            message.serialize(&mut output_buf);
+            message.serialize_for(connection.protocol, &mut output_buf);
             stream
                 .write_all(&output_buf)
                 .await
@@ -83,27 +110,45 @@ async fn handle_connection(
         if let Ok(maybe_bytes_read) = timeout(Duration::ZERO, stream.read(&mut input_buf)).await {
             match maybe_bytes_read {
                 Ok(bytes_read) => {
+                    // The peer has closed the connection
                     if bytes_read == 0 {
-                        continue;
+                        break;
                     }
 
-                    // TODO: Deal with incomplete frames of data
-
-                    let mut input = &input_buf[0..bytes_read];
+                    input.extend_from_slice(&input_buf[0..bytes_read]);
                     while !input.is_empty() {
                         output_buf.clear();
-                        match Message::deserialize(input) {
+                        match Message::deserialize(&input) {
                             Ok((message, remainder)) => {
-                                input = remainder;
+                                let request = input.split_to(input.len() - remainder.len());
+
+                                // Hold the command back for as long as clients are paused,
+                                // checking often in case the pause is lifted early
+                                loop {
+                                    let remaining =
+                                        state.lock().await.pause_remaining(&message, &connection);
+                                    match remaining {
+                                        Some(remaining) => {
+                                            sleep(remaining.min(PAUSE_POLL_INTERVAL)).await
+                                        }
+                                        None => break,
+                                    }
+                                }
+
+                                // Only this connection waits on `DEBUG SLEEP`
+                                if let Message::DebugSleep(duration) = message {
+                                    sleep(duration).await;
+                                }
+
                                 if let Some(response) = state
                                     .lock()
                                     .await
-                                    .handle_incoming(&message, &mut connection)
+                                    .handle_incoming(&message, &request, &mut connection)
                                     .unwrap_or_else(|_| {
                                         panic!("failed to handle message {:?}", message)
                                     })
                                 {
-                                    response.serialize(&mut output_buf);
+                                    response.serialize_for(connection.protocol, &mut output_buf);
                                     stream
                                         .write_all(&output_buf)
                                         .await
@@ -129,23 +174,34 @@ async fn handle_connection(
                                 {
                                     let (s, r) = unbounded_channel::<Message>();
                                     reciever = Some(r);
-                                    replica_senders.lock().await.push(s);
-                                    state.lock().await.add_replica();
+                                    connection.replica_id = state.lock().await.add_replica();
+                                    if let Some(replica_id) = connection.replica_id {
+                                        replica_senders.lock().await.insert(replica_id, s);
+                                    }
                                 }
 
-                                if state.lock().await.is_master()
-                                    && message.is_write_command()
-                                    && matches!(connection.ty, ConnectionType::Client)
-                                {
-                                    for replica in replica_senders.lock().await.iter() {
-                                        replica
-                                            .send(message.clone())
-                                            .expect("failed to propagate message to replica");
-                                    }
+                                // A replica whose connection has gone away has dropped its
+                                // receiver, so it's forgotten rather than sent to
+                                let propagated = state.lock().await.take_propagated();
+                                for message in propagated {
+                                    replica_senders
+                                        .lock()
+                                        .await
+                                        .retain(|_, replica| replica.send(message.clone()).is_ok());
                                 }
                             }
+                            // Wait for the rest of the frame
+                            Err(e) if e.is::<Incomplete>() => break,
                             Err(e) => {
-                                RespValue::SimpleError(&format!("ERR {:?}", e))
+                                // Skip over the bad frame if it's at least valid RESP.
+                                // Otherwise there's no telling where the next frame starts,
+                                // so drop the rest of what we've read.
+                                let consumed = match RespValue::deserialize(&input) {
+                                    Ok((_, remainder)) => input.len() - remainder.len(),
+                                    Err(_) => input.len(),
+                                };
+                                input.advance(consumed);
+                                RespValue::SimpleError(&format!("ERR {}", e))
                                     .serialize(&mut output_buf);
                                 stream
                                     .write_all(&output_buf)
@@ -163,6 +219,14 @@ async fn handle_connection(
             }
         }
     }
+
+    if let Some(monitor_id) = connection.monitor_id {
+        state.lock().await.remove_monitor(monitor_id);
+    }
+    if let Some(replica_id) = connection.replica_id {
+        replica_senders.lock().await.remove(&replica_id);
+        state.lock().await.remove_replica(replica_id);
+    }
 }
 
 #[tokio::main]
@@ -179,7 +243,7 @@ async fn main() -> anyhow::Result<()> {
     let replica_of = config.0.get(&ConfigKey::ReplicaOf).cloned();
     let state = Arc::new(Mutex::new(State::new(config)?));
 
-    let replica_senders = Arc::new(Mutex::new(Vec::new()));
+    let replica_senders = Arc::new(Mutex::new(HashMap::new()));
 
     if state.lock().await.is_slave() {
         let ip_addr = match replica_of.as_ref().unwrap()[0].as_str() {
@@ -196,12 +260,337 @@ async fn main() -> anyhow::Result<()> {
     }
 
     let listener = TcpListener::bind(SocketAddrV4::new(ADDRESS, port)).await?;
+    serve(listener, state, replica_senders, shutdown_signal()).await
+}
+
+/// Accept client connections until `shutdown` completes, then save the database.
+async fn serve(
+    listener: TcpListener,
+    state: Arc<Mutex<State>>,
+    replica_senders: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>>,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    tokio::pin!(shutdown);
     loop {
-        let (stream, _) = listener.accept().await?;
-        let state = state.clone();
-        let replica_senders = replica_senders.clone();
-        tokio::spawn(async move {
-            handle_connection(stream, state, replica_senders, ConnectionType::Client).await;
-        });
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let state = state.clone();
+                let replica_senders = replica_senders.clone();
+                tokio::spawn(async move {
+                    handle_connection(stream, state, replica_senders, ConnectionType::Client)
+                        .await;
+                });
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    state.lock().await.save()
+}
+
+/// Wait for a SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to handle SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, Connection, ConnectionType};
+    use crate::{
+        config::{Config, ConfigKey},
+        message::Message,
+        rdb::read_rdb_file,
+        resp_value::Protocol,
+        state::State,
+        store::StoreData,
+    };
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::{mpsc::unbounded_channel, Mutex},
+        time::sleep,
+    };
+
+    #[tokio::test]
+    async fn save_on_shutdown() {
+        let dir = std::env::temp_dir().join(format!("redis-shutdown-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config(HashMap::from([
+            (ConfigKey::Dir, vec![dir.to_str().unwrap().to_string()]),
+            (ConfigKey::DbFilename, vec!["dump.rdb".to_string()]),
+        ]));
+        let state = Arc::new(Mutex::new(State::new(config).unwrap()));
+        let mut connection = Connection {
+            ty: ConnectionType::Client,
+            send_rdb: false,
+            replica_id: None,
+            db: 0,
+            pending_wait: None,
+            addr: None,
+            monitor_id: None,
+            protocol: Protocol::Resp2,
+        };
+        let set = Message::Set {
+            key: "foo".to_string(),
+            value: "bar".to_string(),
+            expiry: None,
+            keep_ttl: false,
+            condition: None,
+            return_old: false,
+        };
+        state
+            .lock()
+            .await
+            .handle_incoming(&set, &[], &mut connection)
+            .unwrap();
+        // And a key in another database
+        let select = Message::Select { index: 1 };
+        let set_other = Message::Set {
+            key: "other".to_string(),
+            value: "db1".to_string(),
+            expiry: None,
+            keep_ttl: false,
+            condition: None,
+            return_old: false,
+        };
+        for message in [select, set_other] {
+            state
+                .lock()
+                .await
+                .handle_incoming(&message, &[], &mut connection)
+                .unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica_senders = Arc::new(Mutex::new(HashMap::new()));
+        serve(listener, state, replica_senders, async {})
+            .await
+            .unwrap();
+
+        let databases = read_rdb_file(dir.join("dump.rdb"), 16).unwrap();
+        assert_eq!(
+            databases[0].get("foo").unwrap().data,
+            StoreData::String("bar".to_string())
+        );
+        assert!(databases[0].get("other").is_none());
+        assert_eq!(
+            databases[1].get("other").unwrap().data,
+            StoreData::String("db1".to_string())
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn command_split_across_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::new(Config::default()).unwrap()));
+        let replica_senders = Arc::new(Mutex::new(HashMap::new()));
+        let server = tokio::spawn(serve(
+            listener,
+            state,
+            replica_senders,
+            std::future::pending(),
+        ));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let command = b"*2\r\n$4\r\nECHO\r\n$11\r\nhello world\r\n*1\r\n$4\r\nPING\r\n";
+        for chunk in command.chunks(7) {
+            stream.write_all(chunk).await.unwrap();
+            sleep(Duration::from_millis(5)).await;
+        }
+
+        let expected = b"$11\r\nhello world\r\n+PONG\r\n";
+        let mut output = vec![0; expected.len()];
+        stream.read_exact(&mut output).await.unwrap();
+        assert_eq!(output, expected);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn replica_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::new(Config::default()).unwrap()));
+        let replica_senders = Arc::new(Mutex::new(HashMap::new()));
+        let server = tokio::spawn(serve(
+            listener,
+            state,
+            replica_senders.clone(),
+            std::future::pending(),
+        ));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n6380\r\n")
+            .await
+            .unwrap();
+        let mut output = [0; 5];
+        stream.read_exact(&mut output).await.unwrap();
+        assert_eq!(&output, b"+OK\r\n");
+        assert_eq!(replica_senders.lock().await.len(), 1);
+
+        drop(stream);
+        for _ in 0..100 {
+            if replica_senders.lock().await.is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(5)).await;
+        }
+        assert!(replica_senders.lock().await.is_empty());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn propagate_to_dead_replica() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::new(Config::default()).unwrap()));
+        let (sender, receiver) = unbounded_channel();
+        drop(receiver);
+        let replica_senders = Arc::new(Mutex::new(HashMap::from([(0, sender)])));
+        let server = tokio::spawn(serve(
+            listener,
+            state,
+            replica_senders.clone(),
+            std::future::pending(),
+        ));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n")
+            .await
+            .unwrap();
+        let mut output = [0; 5];
+        stream.read_exact(&mut output).await.unwrap();
+        assert_eq!(&output, b"+OK\r\n");
+        assert!(replica_senders.lock().await.is_empty());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn handshake_not_propagated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut state = State::new(Config::default()).unwrap();
+        let (sender, mut receiver) = unbounded_channel();
+        let replica_id = state.add_replica().unwrap();
+        let state = Arc::new(Mutex::new(state));
+        let replica_senders = Arc::new(Mutex::new(HashMap::from([(replica_id, sender)])));
+        let server = tokio::spawn(serve(
+            listener,
+            state,
+            replica_senders,
+            std::future::pending(),
+        ));
+
+        // A second replica connects while the first is already following
+        let mut replica = TcpStream::connect(address).await.unwrap();
+        replica.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        replica
+            .write_all(b"*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n6381\r\n")
+            .await
+            .unwrap();
+        replica
+            .write_all(b"*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n")
+            .await
+            .unwrap();
+        let mut output = [0; 17];
+        replica.read_exact(&mut output).await.unwrap();
+        assert_eq!(&output, b"+PONG\r\n+OK\r\n+OK\r\n");
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n")
+            .await
+            .unwrap();
+        let mut output = [0; 5];
+        client.read_exact(&mut output).await.unwrap();
+        assert_eq!(&output, b"+OK\r\n");
+
+        // Only the client's write reaches the existing replica
+        let mut forwarded = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            forwarded.push(message.name().unwrap());
+        }
+        assert_eq!(forwarded, ["SELECT", "SET"]);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn debug_sleep_blocks_only_its_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::new(Config::default()).unwrap()));
+        let replica_senders = Arc::new(Mutex::new(HashMap::new()));
+        let server = tokio::spawn(serve(
+            listener,
+            state,
+            replica_senders,
+            std::future::pending(),
+        ));
+
+        let mut sleeper = TcpStream::connect(address).await.unwrap();
+        sleeper
+            .write_all(b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n1\r\n")
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(50)).await;
+
+        let start = std::time::Instant::now();
+        let mut other = TcpStream::connect(address).await.unwrap();
+        other.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let mut output = [0; 7];
+        other.read_exact(&mut output).await.unwrap();
+        assert_eq!(&output, b"+PONG\r\n");
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let mut output = [0; 5];
+        sleeper.read_exact(&mut output).await.unwrap();
+        assert_eq!(&output, b"+OK\r\n");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn large_value() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::new(Config::default()).unwrap()));
+        let replica_senders = Arc::new(Mutex::new(HashMap::new()));
+        let server = tokio::spawn(serve(
+            listener,
+            state,
+            replica_senders,
+            std::future::pending(),
+        ));
+
+        let value = (0..1024 * 1024)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect::<String>();
+        let mut command = format!(
+            "*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n${}\r\n{}\r\n",
+            value.len(),
+            value
+        )
+        .into_bytes();
+        command.extend_from_slice(b"*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n");
+
+        // Written in chunks bigger than the server's read buffer
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        for chunk in command.chunks(4000) {
+            stream.write_all(chunk).await.unwrap();
+        }
+
+        let expected = format!("+OK\r\n${}\r\n{}\r\n", value.len(), value);
+        let mut output = vec![0; expected.len()];
+        stream.read_exact(&mut output).await.unwrap();
+        assert!(output == expected.as_bytes());
+        server.abort();
     }
 }
@@ -1,7 +1,12 @@
 use bytes::BytesMut;
 use std::{collections::HashMap, time::Duration};
 
-use crate::{config::ConfigKey, resp_value::RespValue};
+use crate::{
+    command::Command,
+    config::ConfigKey,
+    resp_value::{Protocol, RespValue},
+    store::StoreExpiry,
+};
 
 #[derive(Debug, Clone)]
 pub enum Message {
@@ -13,11 +18,22 @@ pub enum Message {
     InfoResponse {
         sections: HashMap<String, HashMap<String, String>>,
     },
-    KeysRequest,
+    KeysRequest {
+        pattern: String,
+    },
     KeysResponse {
         keys: Vec<String>,
     },
+    Scan {
+        cursor: u64,
+        pattern: Option<String>,
+        count: Option<usize>,
+        type_name: Option<String>,
+    },
+    DbSize,
+    RandomKey,
     CommandDocs,
+    CommandCount,
     Echo(String),
     ReplicationConfig {
         key: String,
@@ -35,16 +51,262 @@ pub enum Message {
     Set {
         key: String,
         value: String,
-        expiry: Option<Duration>,
+        expiry: Option<StoreExpiry>,
+        keep_ttl: bool,
+        condition: Option<SetCondition>,
+        return_old: bool,
     },
     GetRequest {
         key: String,
     },
     GetResponse(GetResponse),
+    Incr {
+        key: String,
+    },
+    Append {
+        key: String,
+        value: String,
+    },
+    LPush {
+        key: String,
+        values: Vec<String>,
+    },
+    RPush {
+        key: String,
+        values: Vec<String>,
+    },
+    LPop {
+        key: String,
+        count: Option<usize>,
+    },
+    RPop {
+        key: String,
+        count: Option<usize>,
+    },
+    LSet {
+        key: String,
+        index: i64,
+        value: String,
+    },
+    SAdd {
+        key: String,
+        members: Vec<String>,
+    },
+    SRem {
+        key: String,
+        members: Vec<String>,
+    },
+    SMembers {
+        key: String,
+    },
+    SCard {
+        key: String,
+    },
+    SIsMember {
+        key: String,
+        member: String,
+    },
+    SPop {
+        key: String,
+        count: Option<usize>,
+    },
+    /// A negative count allows the same member to be returned more than once.
+    SRandMember {
+        key: String,
+        count: Option<i64>,
+    },
+    SMIsMember {
+        key: String,
+        members: Vec<String>,
+    },
+    HSet {
+        key: String,
+        fields: Vec<(String, String)>,
+    },
+    HGetAll {
+        key: String,
+    },
+    HDel {
+        key: String,
+        fields: Vec<String>,
+    },
+    HMGet {
+        key: String,
+        fields: Vec<String>,
+    },
+    HLen {
+        key: String,
+    },
+    HExists {
+        key: String,
+        field: String,
+    },
+    HKeys {
+        key: String,
+    },
+    HVals {
+        key: String,
+    },
+    ZAdd {
+        key: String,
+        condition: Option<SetCondition>,
+        comparison: Option<ScoreComparison>,
+        /// Reply with the number of members added or updated, rather than just added.
+        changed: bool,
+        members: Vec<(f64, String)>,
+    },
+    ZRange {
+        key: String,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+    },
+    ZRank {
+        key: String,
+        member: String,
+    },
+    /// The member's rank in descending score order.
+    ZRevRank {
+        key: String,
+        member: String,
+    },
+    ZRem {
+        key: String,
+        members: Vec<String>,
+    },
+    ZCard {
+        key: String,
+    },
+    ZIncrBy {
+        key: String,
+        amount: f64,
+        member: String,
+    },
+    SInter {
+        keys: Vec<String>,
+    },
+    SUnion {
+        keys: Vec<String>,
+    },
+    /// The members of the first set that aren't in any of the others.
+    SDiff {
+        keys: Vec<String>,
+    },
+    Exists {
+        keys: Vec<String>,
+    },
+    Type {
+        key: String,
+    },
+    ObjectEncoding {
+        key: String,
+    },
+    Select {
+        index: i64,
+    },
+    FlushDb,
+    FlushAll,
+    Touch {
+        keys: Vec<String>,
+    },
+    Del {
+        keys: Vec<String>,
+    },
+    /// Like `Del`, but a candidate for reclaiming memory in the background.
+    Unlink {
+        keys: Vec<String>,
+    },
+    Expire {
+        key: String,
+        seconds: i64,
+    },
+    PExpire {
+        key: String,
+        millis: i64,
+    },
+    ExpireAt {
+        key: String,
+        unix_seconds: i64,
+    },
+    Persist {
+        key: String,
+    },
+    Rename {
+        src: String,
+        dst: String,
+    },
+    RenameNx {
+        src: String,
+        dst: String,
+    },
+    Copy {
+        src: String,
+        dst: String,
+        db: Option<i64>,
+        replace: bool,
+    },
+    Dump {
+        key: String,
+    },
+    Restore {
+        key: String,
+        /// Milliseconds to live, or a unix time in milliseconds with `absolute_ttl`.
+        /// Zero means no expiry.
+        ttl: u64,
+        payload: String,
+        replace: bool,
+        absolute_ttl: bool,
+    },
+    Ttl {
+        key: String,
+    },
+    PTtl {
+        key: String,
+    },
+    PExpireAt {
+        key: String,
+        unix_millis: i64,
+    },
+    GetEx {
+        key: String,
+        expiry_option: Option<ExpiryOption>,
+    },
     ConfigGetRequest {
         key: ConfigKey,
     },
+    /// Block the server, for testing.
+    DebugSleep(Duration),
+    /// Save and reload every database, which should change nothing.
+    DebugReload,
+    LatencyLatest,
+    LatencyHistory {
+        event: String,
+    },
+    LatencyReset {
+        events: Vec<String>,
+    },
+    /// The newest `count` slow log entries, or all of them if there's no count.
+    SlowLogGet {
+        count: Option<usize>,
+    },
+    SlowLogLen,
+    SlowLogReset,
+    /// Stream every command the server handles back to this connection.
+    Monitor,
+    /// Switch to RESP `protover`, if given, and describe the server.
+    Hello {
+        protover: Option<u8>,
+    },
+    ClientPause {
+        timeout: Duration,
+        writes_only: bool,
+    },
+    ClientUnpause,
     ConfigGetResponse(Option<ConfigGetResponse>),
+    ConfigSetRequest {
+        key: ConfigKey,
+        values: Vec<String>,
+    },
     DatabaseFile(Vec<u8>),
     Wait {
         num_replicas: usize,
@@ -53,6 +315,30 @@ pub enum Message {
     WaitReply {
         num_replicas: usize,
     },
+    /// A generic reply to a command.
+    Reply(RespValue<'static>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    /// Only set the key if it doesn't already exist (`NX`).
+    NotExists,
+    /// Only set the key if it already exists (`XX`).
+    Exists,
+}
+
+/// Only update an existing member's score if the new score is greater (`GT`) or
+/// less (`LT`). New members are always added.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreComparison {
+    Greater,
+    Less,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpiryOption {
+    Set(StoreExpiry),
+    Persist,
 }
 
 #[derive(Debug, Clone)]
@@ -68,26 +354,221 @@ pub struct ConfigGetResponse {
 }
 
 impl Message {
-    pub fn is_write_command(&self) -> bool {
-        matches!(self, Message::Set { .. } | Message::GetRequest { .. })
+    /// The arguments of a request exactly as the client sent them, including the
+    /// command name, or nothing if it isn't a command.
+    pub fn request_args(request: &[u8]) -> Vec<String> {
+        match RespValue::deserialize(request) {
+            Ok((RespValue::Array(elements), _)) => elements
+                .iter()
+                .map_while(|e| match e {
+                    RespValue::BulkString(s) => Some(s.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The name of the command the request is sent as, or `None` if it isn't a
+    /// command.
+    pub fn name(&self) -> Option<&'static str> {
+        let name = match self {
+            Message::Ping => "PING",
+            Message::Echo(_) => "ECHO",
+            Message::CommandDocs | Message::CommandCount => "COMMAND",
+            Message::DebugSleep(_) | Message::DebugReload => "DEBUG",
+            Message::LatencyLatest
+            | Message::LatencyHistory { .. }
+            | Message::LatencyReset { .. } => "LATENCY",
+            Message::SlowLogGet { .. } | Message::SlowLogLen | Message::SlowLogReset => "SLOWLOG",
+            Message::Hello { .. } => "HELLO",
+            Message::Monitor => "MONITOR",
+            Message::Set { .. } => "SET",
+            Message::GetRequest { .. } => "GET",
+            Message::GetEx { .. } => "GETEX",
+            Message::Incr { .. } => "INCR",
+            Message::Append { .. } => "APPEND",
+            Message::LPush { .. } => "LPUSH",
+            Message::RPush { .. } => "RPUSH",
+            Message::LPop { .. } => "LPOP",
+            Message::RPop { .. } => "RPOP",
+            Message::LSet { .. } => "LSET",
+            Message::SAdd { .. } => "SADD",
+            Message::SRem { .. } => "SREM",
+            Message::SMembers { .. } => "SMEMBERS",
+            Message::SCard { .. } => "SCARD",
+            Message::SPop { .. } => "SPOP",
+            Message::SRandMember { .. } => "SRANDMEMBER",
+            Message::SIsMember { .. } => "SISMEMBER",
+            Message::HSet { .. } => "HSET",
+            Message::HDel { .. } => "HDEL",
+            Message::HMGet { .. } => "HMGET",
+            Message::HLen { .. } => "HLEN",
+            Message::HExists { .. } => "HEXISTS",
+            Message::HGetAll { .. } => "HGETALL",
+            Message::HKeys { .. } => "HKEYS",
+            Message::HVals { .. } => "HVALS",
+            Message::ZAdd { .. } => "ZADD",
+            Message::ZRange { .. } => "ZRANGE",
+            Message::ZRem { .. } => "ZREM",
+            Message::ZCard { .. } => "ZCARD",
+            Message::ZIncrBy { .. } => "ZINCRBY",
+            Message::ZRank { .. } => "ZRANK",
+            Message::ZRevRank { .. } => "ZREVRANK",
+            Message::SInter { .. } => "SINTER",
+            Message::SUnion { .. } => "SUNION",
+            Message::SDiff { .. } => "SDIFF",
+            Message::SMIsMember { .. } => "SMISMEMBER",
+            Message::Exists { .. } => "EXISTS",
+            Message::FlushDb => "FLUSHDB",
+            Message::FlushAll => "FLUSHALL",
+            Message::Select { .. } => "SELECT",
+            Message::Touch { .. } => "TOUCH",
+            Message::Del { .. } => "DEL",
+            Message::Unlink { .. } => "UNLINK",
+            Message::ObjectEncoding { .. } => "OBJECT",
+            Message::Type { .. } => "TYPE",
+            Message::Expire { .. } => "EXPIRE",
+            Message::PExpire { .. } => "PEXPIRE",
+            Message::ExpireAt { .. } => "EXPIREAT",
+            Message::PExpireAt { .. } => "PEXPIREAT",
+            Message::Persist { .. } => "PERSIST",
+            Message::Rename { .. } => "RENAME",
+            Message::RenameNx { .. } => "RENAMENX",
+            Message::Copy { .. } => "COPY",
+            Message::Dump { .. } => "DUMP",
+            Message::Restore { .. } => "RESTORE",
+            Message::Ttl { .. } => "TTL",
+            Message::PTtl { .. } => "PTTL",
+            Message::ClientPause { .. } | Message::ClientUnpause => "CLIENT",
+            Message::ConfigGetRequest { .. } | Message::ConfigSetRequest { .. } => "CONFIG",
+            Message::KeysRequest { .. } => "KEYS",
+            Message::Scan { .. } => "SCAN",
+            Message::DbSize => "DBSIZE",
+            Message::RandomKey => "RANDOMKEY",
+            Message::InfoRequest { .. } => "INFO",
+            Message::ReplicationConfig { .. } => "REPLCONF",
+            Message::PSync { .. } => "PSYNC",
+            Message::Wait { .. } => "WAIT",
+            Message::Pong
+            | Message::Ok
+            | Message::GetResponse(_)
+            | Message::ConfigGetResponse(_)
+            | Message::KeysResponse { .. }
+            | Message::InfoResponse { .. }
+            | Message::FullResync { .. }
+            | Message::DatabaseFile(_)
+            | Message::WaitReply { .. }
+            | Message::Reply(_) => return None,
+        };
+        Some(name)
     }
 
     pub fn serialize(&self, buf: &mut BytesMut) {
+        self.serialize_for(Protocol::Resp2, buf)
+    }
+
+    pub fn serialize_for(&self, protocol: Protocol, buf: &mut BytesMut) {
         let response_value = match self {
             Message::Ping => RespValue::Array(vec![RespValue::BulkString("PING")]),
             Message::Pong => RespValue::SimpleString("PONG"),
             Message::Echo(s) => RespValue::BulkString(s),
-            Message::CommandDocs => RespValue::Array(vec![]),
+            Message::CommandDocs => RespValue::Array(vec![
+                RespValue::BulkString("COMMAND"),
+                RespValue::BulkString("DOCS"),
+            ]),
+            Message::DebugSleep(duration) => RespValue::Array(vec![
+                RespValue::BulkString("DEBUG"),
+                RespValue::BulkString("SLEEP"),
+                RespValue::OwnedBulkString(duration.as_secs_f64().to_string()),
+            ]),
+            Message::DebugReload => RespValue::Array(vec![
+                RespValue::BulkString("DEBUG"),
+                RespValue::BulkString("RELOAD"),
+            ]),
+            Message::LatencyLatest => RespValue::Array(vec![
+                RespValue::BulkString("LATENCY"),
+                RespValue::BulkString("LATEST"),
+            ]),
+            Message::LatencyHistory { event } => RespValue::Array(vec![
+                RespValue::BulkString("LATENCY"),
+                RespValue::BulkString("HISTORY"),
+                RespValue::BulkString(event),
+            ]),
+            Message::SlowLogGet { count } => {
+                let mut elements = vec![
+                    RespValue::BulkString("SLOWLOG"),
+                    RespValue::BulkString("GET"),
+                ];
+                elements.push(RespValue::OwnedBulkString(match count {
+                    Some(count) => count.to_string(),
+                    None => "-1".to_string(),
+                }));
+                RespValue::Array(elements)
+            }
+            Message::SlowLogLen => RespValue::Array(vec![
+                RespValue::BulkString("SLOWLOG"),
+                RespValue::BulkString("LEN"),
+            ]),
+            Message::Monitor => RespValue::Array(vec![RespValue::BulkString("MONITOR")]),
+            Message::Hello { protover } => {
+                let mut elements = vec![RespValue::BulkString("HELLO")];
+                if let Some(protover) = protover {
+                    elements.push(RespValue::OwnedBulkString(protover.to_string()));
+                }
+                RespValue::Array(elements)
+            }
+            Message::SlowLogReset => RespValue::Array(vec![
+                RespValue::BulkString("SLOWLOG"),
+                RespValue::BulkString("RESET"),
+            ]),
+            Message::LatencyReset { events } => {
+                let mut elements = vec![
+                    RespValue::BulkString("LATENCY"),
+                    RespValue::BulkString("RESET"),
+                ];
+                elements.extend(events.iter().map(|e| RespValue::BulkString(e)));
+                RespValue::Array(elements)
+            }
+            Message::CommandCount => RespValue::Array(vec![
+                RespValue::BulkString("COMMAND"),
+                RespValue::BulkString("COUNT"),
+            ]),
             Message::Ok => RespValue::SimpleString("OK"),
-            Message::Set { key, value, expiry } => {
+            Message::Set {
+                key,
+                value,
+                expiry,
+                keep_ttl,
+                condition,
+                return_old,
+            } => {
                 let mut values = vec![
                     RespValue::BulkString("SET"),
                     RespValue::BulkString(key),
                     RespValue::BulkString(value),
                 ];
-                if let Some(expiry) = expiry {
-                    values.push(RespValue::BulkString("PX"));
-                    values.push(RespValue::OwnedBulkString(expiry.as_millis().to_string()));
+                match expiry {
+                    Some(StoreExpiry::Duration(duration)) => {
+                        values.push(RespValue::BulkString("PX"));
+                        values.push(RespValue::OwnedBulkString(duration.as_millis().to_string()));
+                    }
+                    Some(StoreExpiry::UnixTimestampMillis(millis)) => {
+                        values.push(RespValue::BulkString("PXAT"));
+                        values.push(RespValue::OwnedBulkString(millis.to_string()));
+                    }
+                    None => {}
+                }
+                if *keep_ttl {
+                    values.push(RespValue::BulkString("KEEPTTL"));
+                }
+                match condition {
+                    Some(SetCondition::NotExists) => values.push(RespValue::BulkString("NX")),
+                    Some(SetCondition::Exists) => values.push(RespValue::BulkString("XX")),
+                    None => {}
+                }
+                if *return_old {
+                    values.push(RespValue::BulkString("GET"));
                 }
                 RespValue::Array(values)
             }
@@ -95,10 +576,368 @@ impl Message {
                 RespValue::BulkString("GET"),
                 RespValue::BulkString(key),
             ]),
+            Message::GetEx { key, expiry_option } => {
+                let mut values = vec![RespValue::BulkString("GETEX"), RespValue::BulkString(key)];
+                match expiry_option {
+                    Some(ExpiryOption::Set(StoreExpiry::Duration(duration))) => {
+                        values.push(RespValue::BulkString("PX"));
+                        values.push(RespValue::OwnedBulkString(duration.as_millis().to_string()));
+                    }
+                    Some(ExpiryOption::Set(StoreExpiry::UnixTimestampMillis(millis))) => {
+                        values.push(RespValue::BulkString("PXAT"));
+                        values.push(RespValue::OwnedBulkString(millis.to_string()));
+                    }
+                    Some(ExpiryOption::Persist) => values.push(RespValue::BulkString("PERSIST")),
+                    None => {}
+                }
+                RespValue::Array(values)
+            }
+            Message::Incr { key } => RespValue::Array(vec![
+                RespValue::BulkString("INCR"),
+                RespValue::BulkString(key),
+            ]),
+            Message::Append { key, value } => RespValue::Array(vec![
+                RespValue::BulkString("APPEND"),
+                RespValue::BulkString(key),
+                RespValue::BulkString(value),
+            ]),
+            Message::LPush { key, values } => {
+                let mut elements = vec![RespValue::BulkString("LPUSH"), RespValue::BulkString(key)];
+                elements.extend(values.iter().map(|v| RespValue::BulkString(v)));
+                RespValue::Array(elements)
+            }
+            Message::RPush { key, values } => {
+                let mut elements = vec![RespValue::BulkString("RPUSH"), RespValue::BulkString(key)];
+                elements.extend(values.iter().map(|v| RespValue::BulkString(v)));
+                RespValue::Array(elements)
+            }
+            Message::LPop { key, count } | Message::RPop { key, count } => {
+                let name = if matches!(self, Message::LPop { .. }) {
+                    "LPOP"
+                } else {
+                    "RPOP"
+                };
+                let mut elements = vec![RespValue::BulkString(name), RespValue::BulkString(key)];
+                if let Some(count) = count {
+                    elements.push(RespValue::OwnedBulkString(count.to_string()));
+                }
+                RespValue::Array(elements)
+            }
+            Message::LSet { key, index, value } => RespValue::Array(vec![
+                RespValue::BulkString("LSET"),
+                RespValue::BulkString(key),
+                RespValue::OwnedBulkString(index.to_string()),
+                RespValue::BulkString(value),
+            ]),
+            Message::SAdd { key, members } | Message::SRem { key, members } => {
+                let name = if matches!(self, Message::SAdd { .. }) {
+                    "SADD"
+                } else {
+                    "SREM"
+                };
+                let mut elements = vec![RespValue::BulkString(name), RespValue::BulkString(key)];
+                elements.extend(members.iter().map(|m| RespValue::BulkString(m)));
+                RespValue::Array(elements)
+            }
+            Message::SMembers { key } => RespValue::Array(vec![
+                RespValue::BulkString("SMEMBERS"),
+                RespValue::BulkString(key),
+            ]),
+            Message::SCard { key } => RespValue::Array(vec![
+                RespValue::BulkString("SCARD"),
+                RespValue::BulkString(key),
+            ]),
+            Message::SPop { key, count } => {
+                let mut elements = vec![RespValue::BulkString("SPOP"), RespValue::BulkString(key)];
+                if let Some(count) = count {
+                    elements.push(RespValue::OwnedBulkString(count.to_string()));
+                }
+                RespValue::Array(elements)
+            }
+            Message::SRandMember { key, count } => {
+                let mut elements = vec![
+                    RespValue::BulkString("SRANDMEMBER"),
+                    RespValue::BulkString(key),
+                ];
+                if let Some(count) = count {
+                    elements.push(RespValue::OwnedBulkString(count.to_string()));
+                }
+                RespValue::Array(elements)
+            }
+            Message::SIsMember { key, member } => RespValue::Array(vec![
+                RespValue::BulkString("SISMEMBER"),
+                RespValue::BulkString(key),
+                RespValue::BulkString(member),
+            ]),
+            Message::HSet { key, fields } => {
+                let mut elements = vec![RespValue::BulkString("HSET"), RespValue::BulkString(key)];
+                for (field, value) in fields {
+                    elements.push(RespValue::BulkString(field));
+                    elements.push(RespValue::BulkString(value));
+                }
+                RespValue::Array(elements)
+            }
+            Message::HDel { key, fields } => {
+                let mut elements = vec![RespValue::BulkString("HDEL"), RespValue::BulkString(key)];
+                elements.extend(fields.iter().map(|f| RespValue::BulkString(f)));
+                RespValue::Array(elements)
+            }
+            Message::HMGet { key, fields } => {
+                let mut elements = vec![RespValue::BulkString("HMGET"), RespValue::BulkString(key)];
+                elements.extend(fields.iter().map(|f| RespValue::BulkString(f)));
+                RespValue::Array(elements)
+            }
+            Message::HLen { key } => RespValue::Array(vec![
+                RespValue::BulkString("HLEN"),
+                RespValue::BulkString(key),
+            ]),
+            Message::HExists { key, field } => RespValue::Array(vec![
+                RespValue::BulkString("HEXISTS"),
+                RespValue::BulkString(key),
+                RespValue::BulkString(field),
+            ]),
+            Message::HGetAll { key } | Message::HKeys { key } | Message::HVals { key } => {
+                let name = match self {
+                    Message::HGetAll { .. } => "HGETALL",
+                    Message::HKeys { .. } => "HKEYS",
+                    _ => "HVALS",
+                };
+                RespValue::Array(vec![
+                    RespValue::BulkString(name),
+                    RespValue::BulkString(key),
+                ])
+            }
+            Message::ZAdd {
+                key,
+                condition,
+                comparison,
+                changed,
+                members,
+            } => {
+                let mut elements = vec![RespValue::BulkString("ZADD"), RespValue::BulkString(key)];
+                match condition {
+                    Some(SetCondition::NotExists) => elements.push(RespValue::BulkString("NX")),
+                    Some(SetCondition::Exists) => elements.push(RespValue::BulkString("XX")),
+                    None => {}
+                }
+                match comparison {
+                    Some(ScoreComparison::Greater) => elements.push(RespValue::BulkString("GT")),
+                    Some(ScoreComparison::Less) => elements.push(RespValue::BulkString("LT")),
+                    None => {}
+                }
+                if *changed {
+                    elements.push(RespValue::BulkString("CH"));
+                }
+                for (score, member) in members {
+                    elements.push(RespValue::OwnedBulkString(score.to_string()));
+                    elements.push(RespValue::BulkString(member));
+                }
+                RespValue::Array(elements)
+            }
+            Message::ZRange {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => {
+                let mut elements = vec![
+                    RespValue::BulkString("ZRANGE"),
+                    RespValue::BulkString(key),
+                    RespValue::OwnedBulkString(start.to_string()),
+                    RespValue::OwnedBulkString(stop.to_string()),
+                ];
+                if *with_scores {
+                    elements.push(RespValue::BulkString("WITHSCORES"));
+                }
+                RespValue::Array(elements)
+            }
+            Message::ZRem { key, members } => {
+                let mut elements = vec![RespValue::BulkString("ZREM"), RespValue::BulkString(key)];
+                elements.extend(members.iter().map(|m| RespValue::BulkString(m)));
+                RespValue::Array(elements)
+            }
+            Message::ZCard { key } => RespValue::Array(vec![
+                RespValue::BulkString("ZCARD"),
+                RespValue::BulkString(key),
+            ]),
+            Message::ZIncrBy {
+                key,
+                amount,
+                member,
+            } => RespValue::Array(vec![
+                RespValue::BulkString("ZINCRBY"),
+                RespValue::BulkString(key),
+                RespValue::OwnedBulkString(amount.to_string()),
+                RespValue::BulkString(member),
+            ]),
+            Message::ZRank { key, member } | Message::ZRevRank { key, member } => {
+                let name = match self {
+                    Message::ZRank { .. } => "ZRANK",
+                    _ => "ZREVRANK",
+                };
+                RespValue::Array(vec![
+                    RespValue::BulkString(name),
+                    RespValue::BulkString(key),
+                    RespValue::BulkString(member),
+                ])
+            }
+            Message::SInter { keys } | Message::SUnion { keys } | Message::SDiff { keys } => {
+                let name = match self {
+                    Message::SInter { .. } => "SINTER",
+                    Message::SUnion { .. } => "SUNION",
+                    _ => "SDIFF",
+                };
+                let mut elements = vec![RespValue::BulkString(name)];
+                elements.extend(keys.iter().map(|k| RespValue::BulkString(k)));
+                RespValue::Array(elements)
+            }
+            Message::SMIsMember { key, members } => {
+                let mut elements = vec![
+                    RespValue::BulkString("SMISMEMBER"),
+                    RespValue::BulkString(key),
+                ];
+                elements.extend(members.iter().map(|m| RespValue::BulkString(m)));
+                RespValue::Array(elements)
+            }
+            Message::Exists { keys } => {
+                let mut values = vec![RespValue::BulkString("EXISTS")];
+                values.extend(keys.iter().map(|k| RespValue::BulkString(k)));
+                RespValue::Array(values)
+            }
+            Message::FlushDb => RespValue::Array(vec![RespValue::BulkString("FLUSHDB")]),
+            Message::FlushAll => RespValue::Array(vec![RespValue::BulkString("FLUSHALL")]),
+            Message::Select { index } => RespValue::Array(vec![
+                RespValue::BulkString("SELECT"),
+                RespValue::OwnedBulkString(index.to_string()),
+            ]),
+            Message::Touch { keys } => {
+                let mut values = vec![RespValue::BulkString("TOUCH")];
+                values.extend(keys.iter().map(|k| RespValue::BulkString(k)));
+                RespValue::Array(values)
+            }
+            Message::Del { keys } => {
+                let mut values = vec![RespValue::BulkString("DEL")];
+                values.extend(keys.iter().map(|k| RespValue::BulkString(k)));
+                RespValue::Array(values)
+            }
+            Message::Unlink { keys } => {
+                let mut values = vec![RespValue::BulkString("UNLINK")];
+                values.extend(keys.iter().map(|k| RespValue::BulkString(k)));
+                RespValue::Array(values)
+            }
+            Message::ObjectEncoding { key } => RespValue::Array(vec![
+                RespValue::BulkString("OBJECT"),
+                RespValue::BulkString("ENCODING"),
+                RespValue::BulkString(key),
+            ]),
+            Message::Type { key } => RespValue::Array(vec![
+                RespValue::BulkString("TYPE"),
+                RespValue::BulkString(key),
+            ]),
+            Message::Expire { key, seconds } => RespValue::Array(vec![
+                RespValue::BulkString("EXPIRE"),
+                RespValue::BulkString(key),
+                RespValue::OwnedBulkString(seconds.to_string()),
+            ]),
+            Message::PExpire { key, millis } => RespValue::Array(vec![
+                RespValue::BulkString("PEXPIRE"),
+                RespValue::BulkString(key),
+                RespValue::OwnedBulkString(millis.to_string()),
+            ]),
+            Message::ExpireAt { key, unix_seconds } => RespValue::Array(vec![
+                RespValue::BulkString("EXPIREAT"),
+                RespValue::BulkString(key),
+                RespValue::OwnedBulkString(unix_seconds.to_string()),
+            ]),
+            Message::PExpireAt { key, unix_millis } => RespValue::Array(vec![
+                RespValue::BulkString("PEXPIREAT"),
+                RespValue::BulkString(key),
+                RespValue::OwnedBulkString(unix_millis.to_string()),
+            ]),
+            Message::Persist { key } => RespValue::Array(vec![
+                RespValue::BulkString("PERSIST"),
+                RespValue::BulkString(key),
+            ]),
+            Message::Rename { src, dst } => RespValue::Array(vec![
+                RespValue::BulkString("RENAME"),
+                RespValue::BulkString(src),
+                RespValue::BulkString(dst),
+            ]),
+            Message::RenameNx { src, dst } => RespValue::Array(vec![
+                RespValue::BulkString("RENAMENX"),
+                RespValue::BulkString(src),
+                RespValue::BulkString(dst),
+            ]),
+            Message::Copy {
+                src,
+                dst,
+                db,
+                replace,
+            } => {
+                let mut values = vec![
+                    RespValue::BulkString("COPY"),
+                    RespValue::BulkString(src),
+                    RespValue::BulkString(dst),
+                ];
+                if let Some(db) = db {
+                    values.push(RespValue::BulkString("DB"));
+                    values.push(RespValue::OwnedBulkString(db.to_string()));
+                }
+                if *replace {
+                    values.push(RespValue::BulkString("REPLACE"));
+                }
+                RespValue::Array(values)
+            }
+            Message::Dump { key } => RespValue::Array(vec![
+                RespValue::BulkString("DUMP"),
+                RespValue::BulkString(key),
+            ]),
+            Message::Restore {
+                key,
+                ttl,
+                payload,
+                replace,
+                absolute_ttl,
+            } => {
+                let mut values = vec![
+                    RespValue::BulkString("RESTORE"),
+                    RespValue::BulkString(key),
+                    RespValue::OwnedBulkString(ttl.to_string()),
+                    RespValue::BulkString(payload),
+                ];
+                if *replace {
+                    values.push(RespValue::BulkString("REPLACE"));
+                }
+                if *absolute_ttl {
+                    values.push(RespValue::BulkString("ABSTTL"));
+                }
+                RespValue::Array(values)
+            }
+            Message::Ttl { key } => RespValue::Array(vec![
+                RespValue::BulkString("TTL"),
+                RespValue::BulkString(key),
+            ]),
+            Message::PTtl { key } => RespValue::Array(vec![
+                RespValue::BulkString("PTTL"),
+                RespValue::BulkString(key),
+            ]),
             Message::GetResponse(get_response) => match get_response {
                 GetResponse::Found(value) => RespValue::BulkString(value),
                 GetResponse::NotFound => RespValue::NullBulkString,
             },
+            Message::ClientPause {
+                timeout,
+                writes_only,
+            } => RespValue::Array(vec![
+                RespValue::BulkString("CLIENT"),
+                RespValue::BulkString("PAUSE"),
+                RespValue::OwnedBulkString(timeout.as_millis().to_string()),
+                RespValue::BulkString(if *writes_only { "WRITE" } else { "ALL" }),
+            ]),
+            Message::ClientUnpause => RespValue::Array(vec![
+                RespValue::BulkString("CLIENT"),
+                RespValue::BulkString("UNPAUSE"),
+            ]),
             Message::ConfigGetRequest { key } => RespValue::Array(vec![
                 RespValue::BulkString("CONFIG"),
                 RespValue::BulkString("GET"),
@@ -113,10 +952,48 @@ impl Message {
                 }
                 None => RespValue::NullBulkString,
             },
-            Message::KeysRequest => RespValue::Array(vec![RespValue::BulkString("KEYS")]),
+            Message::ConfigSetRequest { key, values } => {
+                let mut elements = vec![
+                    RespValue::BulkString("CONFIG"),
+                    RespValue::BulkString("SET"),
+                    RespValue::BulkString(key.serialize()),
+                ];
+                elements.extend(values.iter().map(|v| RespValue::BulkString(v)));
+                RespValue::Array(elements)
+            }
+            Message::KeysRequest { pattern } => RespValue::Array(vec![
+                RespValue::BulkString("KEYS"),
+                RespValue::BulkString(pattern),
+            ]),
             Message::KeysResponse { keys } => {
                 RespValue::Array(keys.iter().map(|k| RespValue::BulkString(k)).collect())
             }
+            Message::Scan {
+                cursor,
+                pattern,
+                count,
+                type_name,
+            } => {
+                let mut values = vec![
+                    RespValue::BulkString("SCAN"),
+                    RespValue::OwnedBulkString(cursor.to_string()),
+                ];
+                if let Some(pattern) = pattern {
+                    values.push(RespValue::BulkString("MATCH"));
+                    values.push(RespValue::BulkString(pattern));
+                }
+                if let Some(count) = count {
+                    values.push(RespValue::BulkString("COUNT"));
+                    values.push(RespValue::OwnedBulkString(count.to_string()));
+                }
+                if let Some(type_name) = type_name {
+                    values.push(RespValue::BulkString("TYPE"));
+                    values.push(RespValue::BulkString(type_name));
+                }
+                RespValue::Array(values)
+            }
+            Message::DbSize => RespValue::Array(vec![RespValue::BulkString("DBSIZE")]),
+            Message::RandomKey => RespValue::Array(vec![RespValue::BulkString("RANDOMKEY")]),
             Message::InfoRequest { sections } => {
                 let mut values = vec![RespValue::BulkString("INFO")];
                 values.extend(sections.iter().map(|s| RespValue::BulkString(s)));
@@ -130,11 +1007,7 @@ impl Message {
                         lines.push(format!("{key}:{value}"));
                     }
                 }
-                if lines.is_empty() {
-                    RespValue::NullBulkString
-                } else {
-                    RespValue::OwnedBulkString(lines.join("\n"))
-                }
+                RespValue::OwnedBulkString(lines.join("\n"))
             }
             Message::ReplicationConfig { key, value } => RespValue::Array(vec![
                 RespValue::BulkString("REPLCONF"),
@@ -163,14 +1036,13 @@ impl Message {
                 RespValue::OwnedBulkString(timeout.as_millis().to_string()),
             ]),
             Message::WaitReply { num_replicas } => RespValue::Integer(*num_replicas as i64),
+            Message::Reply(value) => return value.serialize_for(protocol, buf),
         };
-        response_value.serialize(buf);
+        response_value.serialize_for(protocol, buf);
     }
 
     pub fn deserialize(data: &[u8]) -> anyhow::Result<(Self, &[u8])> {
-        if data.is_empty() {
-            return Err(anyhow::format_err!("empty message"));
-        }
+        // An `Incomplete` error from here means more data is needed
         let (response_value, remainder) = RespValue::deserialize(data)?;
 
         match response_value {
@@ -190,167 +1062,34 @@ impl Message {
                 }
                 _ => Err(anyhow::format_err!("unknown message {:?}", s)),
             },
-            RespValue::Array(elements) => match elements.get(0) {
-                Some(RespValue::BulkString(s)) => match s.to_ascii_uppercase().as_str() {
-                    "PING" => Ok((Message::Ping, remainder)),
-                    "ECHO" => match elements.get(1) {
-                        Some(RespValue::BulkString(s)) => {
-                            Ok((Message::Echo(s.to_string()), remainder))
-                        }
-                        _ => Err(anyhow::format_err!("malformed ECHO command")),
-                    },
-                    "COMMAND" => match elements.get(1) {
-                        Some(RespValue::BulkString(s)) => match s.to_ascii_uppercase().as_str() {
-                            "DOCS" => Ok((Message::CommandDocs, remainder)),
-                            _ => Err(anyhow::format_err!("malformed COMMAND DOCS command")),
-                        },
-                        _ => Err(anyhow::format_err!("malformed COMMAND command")),
-                    },
-                    "SET" => {
-                        let key = match elements.get(1) {
-                            Some(RespValue::BulkString(s)) => *s,
-                            _ => return Err(anyhow::format_err!("malformed SET command")),
-                        };
-                        let value = match elements.get(2) {
-                            Some(RespValue::BulkString(s)) => *s,
-                            _ => return Err(anyhow::format_err!("malformed SET command")),
-                        };
-                        let expiry = match elements.get(3) {
-                            Some(RespValue::BulkString(s)) => {
-                                if s.to_ascii_uppercase() == "PX" {
-                                    match elements.get(4) {
-                                        Some(RespValue::BulkString(millis_string)) => {
-                                            if let Ok(millis) = millis_string.parse::<u64>() {
-                                                Some(Duration::from_millis(millis))
-                                            } else {
-                                                None
-                                            }
-                                        }
-                                        _ => None,
-                                    }
-                                } else {
-                                    None
-                                }
-                            }
-                            _ => None,
-                        };
-                        Ok((
-                            Message::Set {
-                                key: key.to_string(),
-                                value: value.to_string(),
-                                expiry,
-                            },
-                            remainder,
-                        ))
-                    }
-                    "GET" => {
-                        let key = match elements.get(1) {
-                            Some(RespValue::BulkString(s)) => *s,
-                            _ => return Err(anyhow::format_err!("malformed GET command")),
-                        };
-                        Ok((
-                            Message::GetRequest {
-                                key: key.to_string(),
-                            },
-                            remainder,
-                        ))
+            RespValue::Array(elements) => {
+                // Commands are made up of bulk strings only
+                match elements
+                    .iter()
+                    .find(|e| !matches!(e, RespValue::BulkString(_)))
+                {
+                    Some(RespValue::NullBulkString) => {
+                        anyhow::bail!("Protocol error: invalid bulk length")
                     }
-                    "CONFIG" => match elements.get(1) {
-                        Some(RespValue::BulkString(s)) => match s.to_ascii_uppercase().as_str() {
-                            "GET" => match elements.get(2) {
-                                Some(RespValue::BulkString(s)) => match ConfigKey::deserialize(s) {
-                                    Ok(key) => Ok((Message::ConfigGetRequest { key }, remainder)),
-                                    Err(_) => {
-                                        Err(anyhow::format_err!("invalid config key {:?}", s))
-                                    }
-                                },
-                                _ => Err(anyhow::format_err!("malformed CONFIG GET command")),
-                            },
-                            command => Err(anyhow::format_err!(
-                                "unhandled CONFIG command {:?}",
-                                command.to_uppercase()
-                            )),
-                        },
-                        _ => Err(anyhow::format_err!("malformed CONFIG command")),
-                    },
-                    "KEYS" => match elements.get(1) {
-                        Some(RespValue::BulkString(_)) => Ok((Message::KeysRequest, remainder)),
-                        _ => Err(anyhow::format_err!("malformed KEYS command",)),
+                    Some(element) => anyhow::bail!(
+                        "Protocol error: expected '$', got '{}'",
+                        element.tag() as char
+                    ),
+                    None => {}
+                }
+                match elements.first() {
+                    Some(RespValue::BulkString(name)) => match Command::lookup(name) {
+                        Some(command) => Ok((command.parse(&elements)?, remainder)),
+                        None => Err(anyhow::format_err!(
+                            "unknown command {:?}",
+                            name.to_uppercase()
+                        )),
                     },
-                    "INFO" => {
-                        let mut sections = Vec::new();
-                        for element in elements.iter().skip(1) {
-                            match element {
-                                RespValue::BulkString(section) => {
-                                    sections.push(section.to_string())
-                                }
-                                _ => return Err(anyhow::format_err!("malformed INFO command",)),
-                            }
-                        }
-                        Ok((Message::InfoRequest { sections }, remainder))
-                    }
-                    "REPLCONF" => {
-                        let key = match elements.get(1) {
-                            Some(RespValue::BulkString(s)) => *s,
-                            _ => return Err(anyhow::format_err!("malformed REPLCONF command")),
-                        };
-                        let value = match elements.get(2) {
-                            Some(RespValue::BulkString(s)) => *s,
-                            _ => return Err(anyhow::format_err!("malformed REPLCONF command")),
-                        };
-                        Ok((
-                            Message::ReplicationConfig {
-                                key: key.to_string(),
-                                value: value.to_string(),
-                            },
-                            remainder,
-                        ))
-                    }
-                    "PSYNC" => {
-                        let replication_id = match elements.get(1) {
-                            Some(RespValue::BulkString(s)) => *s,
-                            _ => return Err(anyhow::format_err!("malformed PSYNC command")),
-                        };
-                        let offset = match elements.get(2) {
-                            Some(RespValue::BulkString(s)) => s.parse::<isize>()?,
-                            _ => return Err(anyhow::format_err!("malformed PSYNC command")),
-                        };
-                        Ok((
-                            Message::PSync {
-                                replication_id: replication_id.to_string(),
-                                offset,
-                            },
-                            remainder,
-                        ))
-                    }
-                    "WAIT" => {
-                        let num_replicas = match elements.get(1) {
-                            Some(RespValue::BulkString(s)) => s.parse::<usize>()?,
-                            _ => return Err(anyhow::format_err!("malformed WAIT command")),
-                        };
-                        let timeout = match elements.get(2) {
-                            Some(RespValue::BulkString(s)) => {
-                                Duration::from_millis(s.parse::<u64>()?)
-                            }
-                            _ => return Err(anyhow::format_err!("malformed WAIT command")),
-                        };
-                        Ok((
-                            Message::Wait {
-                                num_replicas,
-                                timeout,
-                            },
-                            remainder,
-                        ))
-                    }
-                    command => Err(anyhow::format_err!(
-                        "unknown command {:?}",
-                        command.to_uppercase()
+                    _ => Err(anyhow::format_err!(
+                        "requests must start with a bulk string"
                     )),
-                },
-                _ => Err(anyhow::format_err!(
-                    "requests must start with a bulk string"
-                )),
-            },
+                }
+            }
             _ => Err(anyhow::format_err!(
                 "unsupported message: {:?}",
                 response_value
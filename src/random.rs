@@ -0,0 +1,51 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+/// A random number. There's no `rand` crate, but every `RandomState` is seeded with
+/// different random keys, so hashing nothing with one gives a fresh random value.
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// A random index into a collection of `len` elements, which must be non-empty.
+pub fn random_index(len: usize) -> usize {
+    (random_u64() % len as u64) as usize
+}
+
+/// Up to `count` distinct elements of `items`, chosen at random.
+pub fn choose_distinct<T>(mut items: Vec<T>, count: usize) -> Vec<T> {
+    let count = count.min(items.len());
+    // A partial Fisher-Yates shuffle, which only shuffles as many as we need
+    for i in 0..count {
+        let j = i + random_index(items.len() - i);
+        items.swap(i, j);
+    }
+    items.truncate(count);
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_distinct, random_index};
+
+    #[test]
+    fn index_in_range() {
+        for len in 1..20 {
+            assert!(random_index(len) < len);
+        }
+    }
+
+    #[test]
+    fn distinct() {
+        let mut chosen = choose_distinct((0..10).collect(), 4);
+        chosen.sort();
+        chosen.dedup();
+        assert_eq!(chosen.len(), 4);
+        assert!(chosen.iter().all(|n| *n < 10));
+
+        assert_eq!(choose_distinct(vec![1, 2], 5).len(), 2);
+        assert!(choose_distinct(vec![1, 2], 0).is_empty());
+    }
+}
@@ -1,5 +1,14 @@
-use crate::store::{Store, StoreExpiry};
-use std::path::PathBuf;
+use crate::store::{sorted_by_score, Store, StoreData, StoreExpiry};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    time::Instant,
+};
+
+const VERSION: &[u8] = b"0011";
+/// The RDB version written at the end of a `DUMP` payload, in binary rather than
+/// the file header's ASCII.
+const DUMP_VERSION: u16 = 11;
 
 enum OpCode {
     EndOfFile = 0xFF,
@@ -61,20 +70,71 @@ impl TryFrom<u8> for ValueType {
     }
 }
 
-pub fn read_rdb_file<P>(path: P) -> anyhow::Result<Store>
+/// Load the databases saved at `path`, as `database_count` stores. Any database
+/// that isn't in the file is left empty.
+pub fn read_rdb_file<P>(path: P, database_count: usize) -> anyhow::Result<Vec<Store>>
 where
     P: Into<PathBuf>,
 {
     let data = std::fs::read(path.into())?;
-    decode_rdb(&data)
+    decode_rdb(&data, database_count)
 }
 
-#[allow(dead_code)]
-pub fn write_rdb_file<P>(_store: &Store, _path: P) -> anyhow::Result<()>
+/// Save `databases` to `path`, via a temporary file so a crash partway through never
+/// leaves a truncated file behind.
+pub fn write_rdb_file<P>(databases: &[Store], path: P) -> anyhow::Result<()>
 where
     P: Into<PathBuf>,
 {
-    todo!()
+    let path = path.into();
+    let mut temp_path = path.clone().into_os_string();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, encode_rdb(databases))?;
+    std::fs::rename(&temp_path, &path)?;
+    Ok(())
+}
+
+/// Round trip `databases` through the RDB format, as `DEBUG RELOAD` does.
+pub fn reload(databases: &[Store]) -> anyhow::Result<Vec<Store>> {
+    decode_rdb(&encode_rdb(databases), databases.len())
+}
+
+/// Serialize a single value for `DUMP`: its type and RDB encoding, followed by the
+/// RDB version and a checksum. Bulk strings are always text here, so the payload is
+/// hex encoded.
+pub fn dump(value: &StoreData) -> String {
+    let mut data = vec![value_type(value) as u8];
+    encode_value(value, &mut data);
+    data.extend_from_slice(&DUMP_VERSION.to_le_bytes());
+    // A zero checksum tells readers not to check it
+    data.extend_from_slice(&[0; 8]);
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The value in a payload produced by `dump`.
+pub fn restore(payload: &str) -> anyhow::Result<StoreData> {
+    let data = (0..payload.len())
+        .step_by(2)
+        .map(|i| {
+            payload
+                .get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| anyhow::format_err!("invalid payload encoding"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if data.len() < 11 {
+        anyhow::bail!("payload too short");
+    }
+
+    let (value, footer) = data.split_at(data.len() - 10);
+    if u16::from_le_bytes([footer[0], footer[1]]) > DUMP_VERSION {
+        anyhow::bail!("unsupported payload version");
+    }
+    let (value, bytes_read) = parse_value(ValueType::try_from(value[0])?, &value[1..])?;
+    if bytes_read != data.len() - 11 {
+        anyhow::bail!("trailing data in payload");
+    }
+    Ok(value)
 }
 
 enum LengthEncoding {
@@ -82,15 +142,12 @@ enum LengthEncoding {
     Special(SpeciaLengthEncoding),
 }
 
-#[allow(dead_code)]
 enum SpeciaLengthEncoding {
     Integer(usize),
     Compressed,
 }
 
 fn parse_string(data: &[u8]) -> anyhow::Result<(String, usize)> {
-    assert!(!data.is_empty());
-
     let mut bytes_read = 0;
 
     let (length_encoding, bytes_read_encoding) = parse_length_encoding(data)?;
@@ -100,11 +157,12 @@ fn parse_string(data: &[u8]) -> anyhow::Result<(String, usize)> {
     let string = match length_encoding {
         LengthEncoding::Length(len) => {
             bytes_read += len;
-            std::str::from_utf8(&rest[0..len])?.to_string()
+            std::str::from_utf8(take(rest, len)?)?.to_string()
         }
         LengthEncoding::Special(special) => match special {
             SpeciaLengthEncoding::Integer(len) => {
                 bytes_read += len;
+                let rest = take(rest, len)?;
                 match len {
                     1 => rest[0].to_string(),
                     2 => u16::from_be_bytes([rest[0], rest[1]]).to_string(),
@@ -112,17 +170,101 @@ fn parse_string(data: &[u8]) -> anyhow::Result<(String, usize)> {
                     _ => unreachable!(),
                 }
             }
-            SpeciaLengthEncoding::Compressed => todo!(),
+            SpeciaLengthEncoding::Compressed => {
+                anyhow::bail!("compressed strings aren't supported")
+            }
         },
     };
 
     Ok((string, bytes_read))
 }
 
-fn parse_length_encoding(data: &[u8]) -> anyhow::Result<(LengthEncoding, usize)> {
-    assert!(!data.is_empty());
+/// A plain length, which can't use one of the special formats.
+fn parse_length(data: &[u8]) -> anyhow::Result<(usize, usize)> {
+    match parse_length_encoding(data)? {
+        (LengthEncoding::Length(len), bytes_read) => Ok((len, bytes_read)),
+        (LengthEncoding::Special(_), _) => anyhow::bail!("expected a length"),
+    }
+}
+
+/// A value type byte, followed by a key and its value.
+fn parse_key_value(data: &[u8]) -> anyhow::Result<(String, StoreData, usize)> {
+    let value_type = ValueType::try_from(data[0])?;
+    let mut bytes_read = 1;
+    let (key, key_bytes_read) = parse_string(&data[bytes_read..])?;
+    bytes_read += key_bytes_read;
+    let (value, value_bytes_read) = parse_value(value_type, &data[bytes_read..])?;
+    bytes_read += value_bytes_read;
+    Ok((key, value, bytes_read))
+}
+
+fn parse_value(value_type: ValueType, data: &[u8]) -> anyhow::Result<(StoreData, usize)> {
+    if let ValueType::String = value_type {
+        let (string, bytes_read) = parse_string(data)?;
+        return Ok((StoreData::String(string), bytes_read));
+    }
 
-    match data[0] >> 6 {
+    let (len, mut bytes_read) = parse_length(data)?;
+    let value = match value_type {
+        ValueType::List => StoreData::List(
+            (0..len)
+                .map(|_| next_string(data, &mut bytes_read))
+                .collect::<anyhow::Result<VecDeque<_>>>()?,
+        ),
+        ValueType::Set => StoreData::Set(
+            (0..len)
+                .map(|_| next_string(data, &mut bytes_read))
+                .collect::<anyhow::Result<HashSet<_>>>()?,
+        ),
+        ValueType::Hash => StoreData::Hash(
+            (0..len)
+                .map(|_| {
+                    Ok((
+                        next_string(data, &mut bytes_read)?,
+                        next_string(data, &mut bytes_read)?,
+                    ))
+                })
+                .collect::<anyhow::Result<HashMap<_, _>>>()?,
+        ),
+        ValueType::SortedSet => StoreData::SortedSet(
+            (0..len)
+                .map(|_| {
+                    let member = next_string(data, &mut bytes_read)?;
+                    let (score, score_bytes_read) = parse_score(&data[bytes_read..])?;
+                    bytes_read += score_bytes_read;
+                    Ok((member, score))
+                })
+                .collect::<anyhow::Result<HashMap<_, _>>>()?,
+        ),
+        _ => anyhow::bail!("unsupported value type"),
+    };
+    Ok((value, bytes_read))
+}
+
+/// The string at `data[*bytes_read..]`, advancing `bytes_read` past it.
+fn next_string(data: &[u8], bytes_read: &mut usize) -> anyhow::Result<String> {
+    let (string, string_bytes_read) = parse_string(&data[*bytes_read..])?;
+    *bytes_read += string_bytes_read;
+    Ok(string)
+}
+
+/// A sorted set score, stored as a string with a one byte length. Some lengths
+/// are reserved for values that aren't numbers.
+fn parse_score(data: &[u8]) -> anyhow::Result<(f64, usize)> {
+    match take(data, 1)?[0] {
+        253 => Ok((f64::NAN, 1)),
+        254 => Ok((f64::INFINITY, 1)),
+        255 => Ok((f64::NEG_INFINITY, 1)),
+        len => {
+            let len = len as usize;
+            let score = std::str::from_utf8(&take(data, 1 + len)?[1..])?.parse::<f64>()?;
+            Ok((score, 1 + len))
+        }
+    }
+}
+
+fn parse_length_encoding(data: &[u8]) -> anyhow::Result<(LengthEncoding, usize)> {
+    match take(data, 1)?[0] >> 6 {
         0b00 => {
             //The next 6 bits represent the length.
             Ok((LengthEncoding::Length((data[0] & 0x3f) as usize), 1))
@@ -130,6 +272,7 @@ fn parse_length_encoding(data: &[u8]) -> anyhow::Result<(LengthEncoding, usize)>
         0b01 => {
             // Read one additional byte. The combined 14 bits
             // represent the length.
+            let data = take(data, 2)?;
             Ok((
                 LengthEncoding::Length(u16::from_be_bytes([(data[0] & 0x3f), data[1]]) as usize),
                 2,
@@ -138,6 +281,7 @@ fn parse_length_encoding(data: &[u8]) -> anyhow::Result<(LengthEncoding, usize)>
         0b10 => {
             // Discard the remaining 6 bits. The next 4 bytes from the stream
             // represent the length.
+            let data = take(data, 5)?;
             Ok((
                 LengthEncoding::Length(
                     u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize
@@ -153,7 +297,7 @@ fn parse_length_encoding(data: &[u8]) -> anyhow::Result<(LengthEncoding, usize)>
                 0 => Ok((LengthEncoding::Special(SpeciaLengthEncoding::Integer(1)), 1)),
                 1 => Ok((LengthEncoding::Special(SpeciaLengthEncoding::Integer(2)), 1)),
                 2 => Ok((LengthEncoding::Special(SpeciaLengthEncoding::Integer(4)), 1)),
-                3 => todo!("compressed string"),
+                3 => Ok((LengthEncoding::Special(SpeciaLengthEncoding::Compressed), 1)),
                 _ => anyhow::bail!("invalid length encoding special format"),
             }
         }
@@ -161,7 +305,13 @@ fn parse_length_encoding(data: &[u8]) -> anyhow::Result<(LengthEncoding, usize)>
     }
 }
 
-fn decode_rdb(data: &[u8]) -> anyhow::Result<Store> {
+/// The first `len` bytes of `data`, or an error if there aren't that many.
+fn take(data: &[u8], len: usize) -> anyhow::Result<&[u8]> {
+    data.get(..len)
+        .ok_or_else(|| anyhow::format_err!("unexpected end of data"))
+}
+
+fn decode_rdb(data: &[u8], database_count: usize) -> anyhow::Result<Vec<Store>> {
     if data.len() < 18 {
         // Need 18 bytes for magic string (5), version (4), end of file opcode (1), and chucksum (8)
         anyhow::bail!("file too short");
@@ -173,7 +323,10 @@ fn decode_rdb(data: &[u8]) -> anyhow::Result<Store> {
     let _version = std::str::from_utf8(&data[5..9])?.parse::<u16>()?;
     // eprintln!("File version: {}", version);
 
-    let mut store = Store::default();
+    let mut databases = Vec::new();
+    databases.resize_with(database_count.max(1), Store::default);
+    // Keys before the first `SELECTDB` belong to the first database
+    let mut db = 0;
 
     let mut rest = &data[9..];
     while !rest.is_empty() {
@@ -185,11 +338,13 @@ fn decode_rdb(data: &[u8]) -> anyhow::Result<Store> {
                 rest = &rest[rest.len()..];
             }
             Ok(OpCode::SelectDatabase) => {
-                // TODO: I'm not sure this is correct
-                let _database = rest[1];
-                rest = &rest[2..];
-
-                // eprintln!("Select database: {}", database);
+                rest = &rest[1..];
+                let (index, bytes_read) = parse_length(rest)?;
+                rest = &rest[bytes_read..];
+                if index >= databases.len() {
+                    anyhow::bail!("database index {} out of range", index);
+                }
+                db = index;
             }
             Ok(OpCode::ExpireTimeSecs) => {
                 let expiry = StoreExpiry::UnixTimestampMillis(
@@ -197,29 +352,14 @@ fn decode_rdb(data: &[u8]) -> anyhow::Result<Store> {
                 );
 
                 rest = &rest[5..];
-                match ValueType::try_from(rest[0])? {
-                    ValueType::String => {
-                        rest = &rest[1..];
-                        let (key, bytes_read) = parse_string(rest)?;
-                        rest = &rest[bytes_read..];
-                        let (value, bytes_read) = parse_string(rest)?;
-                        rest = &rest[bytes_read..];
-
-                        // eprintln!(
-                        //     "Database key/value pair with expiry: {}, {}, {:?}",
-                        //     key, value, expiry
-                        // );
-                        store.data.insert(
-                            key,
-                            crate::store::StoreValue {
-                                data: value,
-                                updated: std::time::Instant::now(),
-                                expiry: Some(expiry),
-                            },
-                        );
-                    }
-                    _ => todo!(),
-                }
+                let (key, value, bytes_read) = parse_key_value(rest)?;
+                rest = &rest[bytes_read..];
+
+                // eprintln!(
+                //     "Database key/value pair with expiry: {}, {:?}, {:?}",
+                //     key, value, expiry
+                // );
+                databases[db].set_with_expiry(key, value, Some(expiry));
             }
             Ok(OpCode::ExpireTimeMillis) => {
                 let expiry = StoreExpiry::UnixTimestampMillis(u64::from_le_bytes([
@@ -227,47 +367,27 @@ fn decode_rdb(data: &[u8]) -> anyhow::Result<Store> {
                 ]));
 
                 rest = &rest[9..];
-                match ValueType::try_from(rest[0])? {
-                    ValueType::String => {
-                        rest = &rest[1..];
-                        let (key, bytes_read) = parse_string(rest)?;
-                        rest = &rest[bytes_read..];
-                        let (value, bytes_read) = parse_string(rest)?;
-                        rest = &rest[bytes_read..];
-
-                        // eprintln!(
-                        //     "Database key/value pair with expiry: {}, {}, {:?}",
-                        //     key, value, expiry
-                        // );
-                        store.data.insert(
-                            key,
-                            crate::store::StoreValue {
-                                data: value,
-                                updated: std::time::Instant::now(),
-                                expiry: Some(expiry),
-                            },
-                        );
-                    }
-                    _ => todo!(),
-                }
+                let (key, value, bytes_read) = parse_key_value(rest)?;
+                rest = &rest[bytes_read..];
+
+                // eprintln!(
+                //     "Database key/value pair with expiry: {}, {:?}, {:?}",
+                //     key, value, expiry
+                // );
+                databases[db].set_with_expiry(key, value, Some(expiry));
             }
             Ok(OpCode::ResizeDatabase) => {
-                // rest = &rest[1..];
-                // let (database_hash_table_size, bytes_read) = parse_string(&rest)?;
-                // rest = &rest[bytes_read..];
-                // let (expiry_hash_table_size, bytes_read) = parse_string(&rest)?;
-                // rest = &rest[bytes_read..];
-
-                // TODO: I don't think this is correct for larger numbers
-                let database_hash_table_size = rest[1];
-                let _expiry_hash_table_size = rest[2];
-                rest = &rest[3..];
+                rest = &rest[1..];
+                let (database_hash_table_size, bytes_read) = parse_length(rest)?;
+                rest = &rest[bytes_read..];
+                let (_expiry_hash_table_size, bytes_read) = parse_length(rest)?;
+                rest = &rest[bytes_read..];
 
                 // eprintln!(
                 //     "Resize database: db hash table size {}, expiry hash table size {}",
                 //     database_hash_table_size, expiry_hash_table_size
                 // );
-                store.data.reserve(database_hash_table_size as usize);
+                databases[db].reserve(database_hash_table_size);
             }
             Ok(OpCode::Auxiliary) => {
                 rest = &rest[1..];
@@ -278,59 +398,167 @@ fn decode_rdb(data: &[u8]) -> anyhow::Result<Store> {
 
                 // eprintln!("Aux key/value pair: {}, {}", key, value);
             }
-            Err(_) => match ValueType::try_from(rest[0])? {
-                ValueType::String => {
-                    rest = &rest[1..];
-                    let (key, bytes_read) = parse_string(rest)?;
-                    rest = &rest[bytes_read..];
-                    let (value, bytes_read) = parse_string(rest)?;
-                    rest = &rest[bytes_read..];
-
-                    // eprintln!("Database key/value pair: {}, {}", key, value);
-                    store.data.insert(
-                        key,
-                        crate::store::StoreValue {
-                            data: value,
-                            updated: std::time::Instant::now(),
-                            expiry: None,
-                        },
-                    );
+            Err(_) => {
+                let (key, value, bytes_read) = parse_key_value(rest)?;
+                rest = &rest[bytes_read..];
+
+                // eprintln!("Database key/value pair: {}, {:?}", key, value);
+                databases[db].set(key, value);
+            }
+        }
+    }
+
+    Ok(databases)
+}
+
+/// Encode `databases` as an RDB file, with a section for each one that has keys.
+/// Keys and the elements of unordered collections are written in sorted order, so
+/// the same contents always encode to the same bytes.
+fn encode_rdb(databases: &[Store]) -> Vec<u8> {
+    let mut data = b"REDIS".to_vec();
+    data.extend_from_slice(VERSION);
+
+    let now = Instant::now();
+    for (index, store) in databases.iter().enumerate() {
+        if store.iter_live().next().is_some() {
+            data.push(OpCode::SelectDatabase as u8);
+            encode_length(index, &mut data);
+            encode_database(store, now, &mut data);
+        }
+    }
+
+    data.push(OpCode::EndOfFile as u8);
+    // A zero checksum tells readers not to check it
+    data.extend_from_slice(&[0; 8]);
+    data
+}
+
+/// The keys of a single database, after its `SELECTDB`.
+fn encode_database(store: &Store, now: Instant, data: &mut Vec<u8>) {
+    let mut entries = store
+        .iter_live()
+        .map(|(key, value)| {
+            (
+                key,
+                value,
+                value.expiry_from(now).map(StoreExpiry::absolute),
+            )
+        })
+        .collect::<Vec<_>>();
+    entries.sort_unstable_by_key(|(key, _, _)| *key);
+    data.push(OpCode::ResizeDatabase as u8);
+    encode_length(entries.len(), data);
+    encode_length(
+        entries
+            .iter()
+            .filter(|(_, _, expiry)| expiry.is_some())
+            .count(),
+        data,
+    );
+
+    for (key, value, expiry) in entries {
+        if let Some(StoreExpiry::UnixTimestampMillis(millis)) = expiry {
+            data.push(OpCode::ExpireTimeMillis as u8);
+            data.extend_from_slice(&millis.to_le_bytes());
+        }
+        data.push(value_type(&value.data) as u8);
+        encode_string(key, data);
+        encode_value(&value.data, data);
+    }
+}
+
+fn value_type(value: &StoreData) -> ValueType {
+    match value {
+        StoreData::String(_) => ValueType::String,
+        StoreData::List(_) => ValueType::List,
+        StoreData::Set(_) => ValueType::Set,
+        StoreData::Hash(_) => ValueType::Hash,
+        StoreData::SortedSet(_) => ValueType::SortedSet,
+    }
+}
+
+fn encode_value(value: &StoreData, data: &mut Vec<u8>) {
+    match value {
+        StoreData::String(s) => encode_string(s, data),
+        StoreData::List(list) => {
+            encode_length(list.len(), data);
+            list.iter().for_each(|e| encode_string(e, data));
+        }
+        StoreData::Set(set) => {
+            let mut members = set.iter().collect::<Vec<_>>();
+            members.sort_unstable();
+            encode_length(members.len(), data);
+            members.into_iter().for_each(|e| encode_string(e, data));
+        }
+        StoreData::Hash(hash) => {
+            let mut fields = hash.iter().collect::<Vec<_>>();
+            fields.sort_unstable();
+            encode_length(fields.len(), data);
+            for (field, value) in fields {
+                encode_string(field, data);
+                encode_string(value, data);
+            }
+        }
+        StoreData::SortedSet(sorted_set) => {
+            encode_length(sorted_set.len(), data);
+            for (member, score) in sorted_by_score(sorted_set) {
+                encode_string(member, data);
+                if score.is_nan() {
+                    data.push(253);
+                } else if score == f64::INFINITY {
+                    data.push(254);
+                } else if score == f64::NEG_INFINITY {
+                    data.push(255);
+                } else {
+                    // Debug formatting switches to an exponent for very large or small
+                    // scores, so it always fits in the one byte length
+                    let score = format!("{:?}", score);
+                    data.push(score.len() as u8);
+                    data.extend_from_slice(score.as_bytes());
                 }
-                _ => todo!(),
-            },
+            }
         }
     }
+}
 
-    Ok(store)
+fn encode_string(s: &str, data: &mut Vec<u8>) {
+    encode_length(s.len(), data);
+    data.extend_from_slice(s.as_bytes());
 }
 
-#[allow(dead_code)]
-fn encode_rdb(_store: &Store) -> anyhow::Result<Vec<u8>> {
-    todo!()
+fn encode_length(len: usize, data: &mut Vec<u8>) {
+    if len < 1 << 6 {
+        data.push(len as u8);
+    } else if len < 1 << 14 {
+        data.extend_from_slice(&(len as u16 | 0x4000).to_be_bytes());
+    } else {
+        data.push(0x80);
+        data.extend_from_slice(&(len as u32).to_be_bytes());
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{decode_rdb, read_rdb_file};
+    use super::{decode_rdb, dump, encode_rdb, read_rdb_file, restore};
+    use crate::store::{Store, StoreData, StoreExpiry};
 
     #[test]
     fn file_too_short() {
-        let result = decode_rdb(b"REDIS");
+        let result = decode_rdb(b"REDIS", 1);
         assert!(result.is_err());
     }
 
     #[test]
     fn invalid_magic_string() {
-        let result = decode_rdb(b"REDDI0001FF00000000");
+        let result = decode_rdb(b"REDDI0001FF00000000", 1);
         assert!(result.is_err());
     }
 
     #[test]
     fn example_dump() {
-        let store = read_rdb_file("tests/test.rdb").unwrap();
-        assert!(store.data.contains_key("mykey"));
-        let value = store.data.get("mykey").unwrap();
-        assert_eq!(value.data, "myval")
+        let databases = read_rdb_file("tests/test.rdb", 1).unwrap();
+        let value = databases[0].get("mykey").unwrap();
+        assert_eq!(value.data, StoreData::String("myval".to_string()))
     }
 
     #[test]
@@ -341,7 +569,7 @@ mod tests {
             64, 254, 0, 251, 5, 1, 0, 6, 111, 114, 97, 110, 103, 101, 5, 97, 112, 112, 108, 101,
             255, 216, 107, 239, 211, 200, 206, 207, 54, 10,
         ];
-        let _result = decode_rdb(data);
+        let _result = decode_rdb(data, 1);
     }
 
     #[test]
@@ -358,6 +586,111 @@ mod tests {
             110, 97, 10, 115, 116, 114, 97, 119, 98, 101, 114, 114, 121, 255, 146, 155, 95, 143,
             105, 232, 19, 170, 10,
         ];
-        let _result = decode_rdb(data);
+        let _result = decode_rdb(data, 1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut store = Store::default();
+        store.set("string".to_string(), StoreData::String("value".to_string()));
+        store.set("long".to_string(), StoreData::String("x".repeat(20_000)));
+        store.set_with_expiry(
+            "expiring".to_string(),
+            StoreData::String("soon".to_string()),
+            Some(StoreExpiry::UnixTimestampMillis(u64::MAX / 2)),
+        );
+        store.set(
+            "list".to_string(),
+            StoreData::List(["a", "b", "a"].map(String::from).into()),
+        );
+        store.set(
+            "set".to_string(),
+            StoreData::Set(["a", "b"].map(String::from).into()),
+        );
+        store.set(
+            "hash".to_string(),
+            StoreData::Hash([("field".to_string(), "value".to_string())].into()),
+        );
+        store.set(
+            "zset".to_string(),
+            StoreData::SortedSet(
+                [("a", 1.5), ("b", -1e300), ("c", f64::INFINITY)]
+                    .map(|(m, s)| (m.to_string(), s))
+                    .into(),
+            ),
+        );
+        for i in 0..100 {
+            store.set(format!("key:{i}"), StoreData::String(i.to_string()));
+        }
+
+        let mut other = Store::default();
+        other.set("other".to_string(), StoreData::String("db2".to_string()));
+        let databases = [store, Store::default(), other];
+
+        let decoded = decode_rdb(&encode_rdb(&databases), 3).unwrap();
+        assert_eq!(decoded.len(), 3);
+        for (store, decoded) in databases.iter().zip(&decoded) {
+            assert_eq!(decoded.iter_live().count(), store.iter_live().count());
+            for (key, value) in store.iter_live() {
+                let decoded_value = decoded.get(key).unwrap();
+                assert_eq!(decoded_value.data, value.data);
+                assert_eq!(decoded_value.expiry, value.expiry);
+            }
+        }
+
+        // Every database in the file has to fit
+        assert!(decode_rdb(&encode_rdb(&databases), 2).is_err());
+    }
+
+    #[test]
+    fn deterministic() {
+        // Each store's maps get their own random hash keys, so the same contents
+        // iterate in different orders
+        let build = |keys: &mut dyn Iterator<Item = usize>| {
+            let mut store = Store::default();
+            for i in keys {
+                store.set(format!("string:{i}"), StoreData::String(i.to_string()));
+                store.set(
+                    format!("set:{i}"),
+                    StoreData::Set((0..20).map(|m| m.to_string()).collect()),
+                );
+                store.set(
+                    format!("hash:{i}"),
+                    StoreData::Hash((0..20).map(|f| (f.to_string(), i.to_string())).collect()),
+                );
+                store.set(
+                    format!("zset:{i}"),
+                    StoreData::SortedSet((0..20).map(|m| (m.to_string(), 1.0)).collect()),
+                );
+            }
+            store
+        };
+        let store = [build(&mut (0..50))];
+        let other = [build(&mut (0..50).rev())];
+
+        assert_eq!(encode_rdb(&store), encode_rdb(&store));
+        assert_eq!(encode_rdb(&store), encode_rdb(&other));
+    }
+
+    #[test]
+    fn dump_restore() {
+        let values = [
+            StoreData::String("value".to_string()),
+            StoreData::String("12345".to_string()),
+            StoreData::List(["a", "b", "a"].map(String::from).into()),
+            StoreData::Set(["a", "b"].map(String::from).into()),
+            StoreData::Hash([("field".to_string(), "value".to_string())].into()),
+            StoreData::SortedSet([("a".to_string(), 1.5)].into()),
+        ];
+        for value in values {
+            assert_eq!(restore(&dump(&value)).unwrap(), value);
+        }
+
+        let payload = dump(&StoreData::String("value".to_string()));
+        assert!(restore(&payload[..payload.len() - 2]).is_err());
+        assert!(restore(&format!("{payload}00")).is_err());
+        assert!(restore("not hex").is_err());
+        // A compressed string
+        assert!(restore("00c30b000000000000000000").is_err());
     }
 }
@@ -2,6 +2,20 @@ use bytes::{BufMut, BytesMut};
 
 const TERMINATOR: &[u8] = b"\r\n";
 
+/// The error for a value that's cut off partway through, which may be completed by
+/// reading more data.
+#[derive(Debug, thiserror::Error)]
+#[error("incomplete frame")]
+pub struct Incomplete;
+
+/// The RESP version a client has chosen, which decides how some values are encoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 #[allow(dead_code)]
 pub enum RespValue<'data> {
@@ -19,15 +33,35 @@ pub enum RespValue<'data> {
     Boolean(bool),
     Double(f64),
     BigNumber(&'data str),
-    BulkError,
-    VerbatimString,
-    Map,
-    Set,
-    Push,
+    /// An error that, unlike a simple error, can contain newlines.
+    BulkError(String),
+    /// A string with a three-character encoding, like `txt` or `mkd`.
+    VerbatimString {
+        encoding: [u8; 3],
+        data: String,
+    },
+    Map(Vec<(RespValue<'data>, RespValue<'data>)>),
+    Set(Vec<RespValue<'data>>),
+    /// Out-of-band data, like pub/sub messages, encoded exactly like an array.
+    Push(Vec<RespValue<'data>>),
+}
+
+impl RespValue<'static> {
+    /// An array of bulk strings that owns its elements, for replies built from
+    /// computed or copied strings.
+    pub fn bulk_strings<S: Into<String>>(strings: impl IntoIterator<Item = S>) -> Self {
+        RespValue::Array(
+            strings
+                .into_iter()
+                .map(|s| RespValue::OwnedBulkString(s.into()))
+                .collect(),
+        )
+    }
 }
 
 impl<'data> RespValue<'data> {
-    fn tag(&self) -> u8 {
+    /// The type byte this value starts with on the wire.
+    pub fn tag(&self) -> u8 {
         match self {
             RespValue::OwnedSimpleString(_) => b'+',
             RespValue::SimpleString(_) => b'+',
@@ -43,11 +77,11 @@ impl<'data> RespValue<'data> {
             RespValue::Boolean(_) => b'#',
             RespValue::Double(_) => b',',
             RespValue::BigNumber { .. } => b'(',
-            RespValue::BulkError => b'!',
-            RespValue::VerbatimString => b'=',
-            RespValue::Map => b'%',
-            RespValue::Set => b'~',
-            RespValue::Push => b'>',
+            RespValue::BulkError(_) => b'!',
+            RespValue::VerbatimString { .. } => b'=',
+            RespValue::Map(_) => b'%',
+            RespValue::Set(_) => b'~',
+            RespValue::Push(_) => b'>',
         }
     }
 
@@ -67,16 +101,68 @@ impl<'data> RespValue<'data> {
             RespValue::Boolean(_) => true,
             RespValue::Double(_) => true,
             RespValue::BigNumber(_) => true,
-            RespValue::BulkError => false,
-            RespValue::VerbatimString => false,
-            RespValue::Map => false,
-            RespValue::Set => false,
-            RespValue::Push => false,
+            RespValue::BulkError(_) => true,
+            RespValue::VerbatimString { .. } => true,
+            RespValue::Map(_) => false,
+            RespValue::Set(_) => false,
+            RespValue::Push(_) => false,
         }
     }
 
+    /// Serialize exactly as is, whichever protocol the value belongs to.
     pub fn serialize(&self, buf: &mut BytesMut) {
-        buf.put_u8(self.tag());
+        self.write(None, buf)
+    }
+
+    /// Serialize for a client speaking `protocol`. RESP3 has a single null type,
+    /// which replaces both the null bulk string and the null array, and RESP2
+    /// clients get the nearest RESP2 type in place of any RESP3 one.
+    pub fn serialize_for(&self, protocol: Protocol, buf: &mut BytesMut) {
+        self.write(Some(protocol), buf)
+    }
+
+    fn write(&self, protocol: Option<Protocol>, buf: &mut BytesMut) {
+        match (protocol, self) {
+            (Some(Protocol::Resp3), RespValue::NullBulkString | RespValue::NullArray) => {
+                return RespValue::Null.write(protocol, buf);
+            }
+            (Some(Protocol::Resp2), RespValue::Null) => {
+                return RespValue::NullBulkString.write(protocol, buf);
+            }
+            (Some(Protocol::Resp2), RespValue::Boolean(b)) => {
+                return RespValue::Integer(*b as i64).write(protocol, buf);
+            }
+            (Some(Protocol::Resp2), RespValue::Double(f)) => {
+                return RespValue::OwnedBulkString(f.to_string()).write(protocol, buf);
+            }
+            (Some(Protocol::Resp2), RespValue::BigNumber(digits)) => {
+                return RespValue::BulkString(digits).write(protocol, buf);
+            }
+            (Some(Protocol::Resp2), RespValue::VerbatimString { data, .. }) => {
+                return RespValue::BulkString(data).write(protocol, buf);
+            }
+            (Some(Protocol::Resp2), RespValue::BulkError(message)) => {
+                // A simple error has to fit on one line
+                buf.put_u8(b'-');
+                buf.put(message.replace(['\r', '\n'], " ").as_bytes());
+                buf.put(TERMINATOR);
+                return;
+            }
+            (Some(Protocol::Resp2), RespValue::Map(entries)) => {
+                // A flat array of keys and values
+                buf.put_u8(b'*');
+                buf.put((entries.len() * 2).to_string().as_bytes());
+                buf.put(TERMINATOR);
+                for (key, value) in entries.iter() {
+                    key.write(protocol, buf);
+                    value.write(protocol, buf);
+                }
+                return;
+            }
+            (Some(Protocol::Resp2), RespValue::Set(_) | RespValue::Push(_)) => buf.put_u8(b'*'),
+            _ => buf.put_u8(self.tag()),
+        }
+
         match self {
             RespValue::OwnedSimpleString(s) => {
                 buf.put(s.as_bytes());
@@ -105,11 +191,11 @@ impl<'data> RespValue<'data> {
                 buf.put(TERMINATOR);
                 buf.put(*b);
             }
-            RespValue::Array(elements) => {
+            RespValue::Array(elements) | RespValue::Set(elements) | RespValue::Push(elements) => {
                 buf.put(elements.len().to_string().as_bytes());
                 buf.put(TERMINATOR);
                 for e in elements.iter() {
-                    e.serialize(buf);
+                    e.write(protocol, buf);
                 }
             }
             RespValue::Null => {}
@@ -122,11 +208,26 @@ impl<'data> RespValue<'data> {
             RespValue::BigNumber(digits) => {
                 buf.put(digits.as_bytes());
             }
-            RespValue::BulkError => todo!(),
-            RespValue::VerbatimString => todo!(),
-            RespValue::Map => todo!(),
-            RespValue::Set => todo!(),
-            RespValue::Push => todo!(),
+            RespValue::BulkError(message) => {
+                buf.put(message.len().to_string().as_bytes());
+                buf.put(TERMINATOR);
+                buf.put(message.as_bytes());
+            }
+            RespValue::VerbatimString { encoding, data } => {
+                buf.put((data.len() + 4).to_string().as_bytes());
+                buf.put(TERMINATOR);
+                buf.put(&encoding[..]);
+                buf.put_u8(b':');
+                buf.put(data.as_bytes());
+            }
+            RespValue::Map(entries) => {
+                buf.put(entries.len().to_string().as_bytes());
+                buf.put(TERMINATOR);
+                for (key, value) in entries.iter() {
+                    key.write(protocol, buf);
+                    value.write(protocol, buf);
+                }
+            }
         }
         if self.has_final_terminator() {
             buf.put(TERMINATOR);
@@ -134,7 +235,9 @@ impl<'data> RespValue<'data> {
     }
 
     pub fn deserialize(data: &'data [u8]) -> anyhow::Result<(Self, &'data [u8])> {
-        assert!(!data.is_empty());
+        if data.is_empty() {
+            return Err(Incomplete.into());
+        }
 
         match data[0] {
             b'+' => {
@@ -145,7 +248,7 @@ impl<'data> RespValue<'data> {
                         &data[terminator_index + 2..],
                     ))
                 } else {
-                    Err(anyhow::format_err!("unterminated simple string"))
+                    Err(Incomplete.into())
                 }
             }
             b'-' => {
@@ -156,7 +259,7 @@ impl<'data> RespValue<'data> {
                         &data[terminator_index + 2..],
                     ))
                 } else {
-                    Err(anyhow::format_err!("unterminated simple error"))
+                    Err(Incomplete.into())
                 }
             }
             b':' => {
@@ -172,7 +275,7 @@ impl<'data> RespValue<'data> {
                         Err(anyhow::format_err!("invalid integer"))
                     }
                 } else {
-                    Err(anyhow::format_err!("unterminated integer"))
+                    Err(Incomplete.into())
                 }
             }
             b'$' => {
@@ -181,27 +284,22 @@ impl<'data> RespValue<'data> {
                 if let Some(terminator_index) = find_terminator(data) {
                     if let Ok(digits_str) = std::str::from_utf8(&data[1..terminator_index]) {
                         if let Ok(data_len) = digits_str.parse::<usize>() {
-                            if data.len() == terminator_index + 2 + data_len
-                                || &data[terminator_index + 2 + data_len
-                                    ..terminator_index + 2 + data_len + 2]
-                                    != TERMINATOR
-                            {
+                            let Some(end) = (terminator_index + 2).checked_add(data_len) else {
+                                return Err(anyhow::format_err!("invalid bulk string length"));
+                            };
+                            if data.len() < end || (data.len() == end + 1 && data[end] == b'\r') {
+                                // Either the data or the terminator after it is cut off
+                                Err(Incomplete.into())
+                            } else if data.get(end..end + 2) != Some(TERMINATOR) {
                                 // Raw bytes
-                                let bytes =
-                                    &data[terminator_index + 2..terminator_index + 2 + data_len];
-                                Ok((
-                                    RespValue::RawBytes(bytes),
-                                    &data[terminator_index + 2 + data_len..],
-                                ))
+                                let bytes = &data[terminator_index + 2..end];
+                                Ok((RespValue::RawBytes(bytes), &data[end..]))
                             } else {
                                 // Bulk string
-                                if let Ok(string) = std::str::from_utf8(
-                                    &data[terminator_index + 2..terminator_index + 2 + data_len],
-                                ) {
-                                    Ok((
-                                        RespValue::BulkString(string),
-                                        &data[terminator_index + 2 + data_len + 2..],
-                                    ))
+                                if let Ok(string) =
+                                    std::str::from_utf8(&data[terminator_index + 2..end])
+                                {
+                                    Ok((RespValue::BulkString(string), &data[end + 2..]))
                                 } else {
                                     Err(anyhow::format_err!("invalid bulk string"))
                                 }
@@ -216,7 +314,7 @@ impl<'data> RespValue<'data> {
                         Err(anyhow::format_err!("invalid bulk string/raw bytes"))
                     }
                 } else {
-                    Err(anyhow::format_err!("invalid bulk string/raw bytes"))
+                    Err(Incomplete.into())
                 }
             }
             b'*' => {
@@ -227,7 +325,7 @@ impl<'data> RespValue<'data> {
                             let mut rest = &data[terminator_index + 2..];
                             let mut elements = Vec::new();
                             for _ in 0..num_elements {
-                                let result = RespValue::deserialize(rest)?;
+                                let result = deserialize_element(rest)?;
                                 elements.push(result.0);
                                 rest = result.1;
                             }
@@ -242,7 +340,7 @@ impl<'data> RespValue<'data> {
                         Err(anyhow::format_err!("invalid array"))
                     }
                 } else {
-                    Err(anyhow::format_err!("unterminated array"))
+                    Err(Incomplete.into())
                 }
             }
             b'_' => {
@@ -254,7 +352,7 @@ impl<'data> RespValue<'data> {
                         Err(anyhow::format_err!("non-empty null"))
                     }
                 } else {
-                    Err(anyhow::format_err!("unterminated null"))
+                    Err(Incomplete.into())
                 }
             }
             b'#' => {
@@ -270,7 +368,7 @@ impl<'data> RespValue<'data> {
                         Err(anyhow::format_err!("invalid boolean"))
                     }
                 } else {
-                    Err(anyhow::format_err!("unterminated boolean"))
+                    Err(Incomplete.into())
                 }
             }
             b',' => {
@@ -286,7 +384,7 @@ impl<'data> RespValue<'data> {
                         Err(anyhow::format_err!("invalid double"))
                     }
                 } else {
-                    Err(anyhow::format_err!("unterminated double"))
+                    Err(Incomplete.into())
                 }
             }
             b'(' => {
@@ -305,39 +403,181 @@ impl<'data> RespValue<'data> {
                         Err(anyhow::format_err!("invalid big number"))
                     }
                 } else {
-                    Err(anyhow::format_err!("unterminated big number"))
+                    Err(Incomplete.into())
                 }
             }
             b'!' => {
                 // Bulk error: "!<length>\r\n<error>\r\n"
-                todo!("bulk error");
+                if let Some(terminator_index) = find_terminator(data) {
+                    let start = terminator_index + 2;
+                    match std::str::from_utf8(&data[1..terminator_index])?.parse::<usize>() {
+                        Ok(len)
+                            if start
+                                .checked_add(len)
+                                .and_then(|e| e.checked_add(2))
+                                .is_none() =>
+                        {
+                            Err(anyhow::format_err!("invalid bulk error length"))
+                        }
+                        Ok(len)
+                            if data.len() >= start + len + 2
+                                && &data[start + len..start + len + 2] == TERMINATOR =>
+                        {
+                            let message = std::str::from_utf8(&data[start..start + len])?;
+                            Ok((
+                                RespValue::BulkError(message.to_string()),
+                                &data[start + len + 2..],
+                            ))
+                        }
+                        Ok(len) if data.len() < start + len + 2 => Err(Incomplete.into()),
+                        _ => Err(anyhow::format_err!("invalid bulk error")),
+                    }
+                } else {
+                    Err(Incomplete.into())
+                }
             }
             b'=' => {
-                // Bulk string: "=<length>\r\n<encoding>:<data>\r\n"
-                todo!("verbatim string");
+                // Verbatim string: "=<length>\r\n<encoding>:<data>\r\n"
+                if let Some(terminator_index) = find_terminator(data) {
+                    let start = terminator_index + 2;
+                    match std::str::from_utf8(&data[1..terminator_index])?.parse::<usize>() {
+                        Ok(len)
+                            if start
+                                .checked_add(len)
+                                .and_then(|e| e.checked_add(2))
+                                .is_none() =>
+                        {
+                            Err(anyhow::format_err!("invalid verbatim string length"))
+                        }
+                        Ok(len)
+                            if len >= 4
+                                && data.len() >= start + len + 2
+                                && data[start + 3] == b':'
+                                && &data[start + len..start + len + 2] == TERMINATOR =>
+                        {
+                            let encoding = [data[start], data[start + 1], data[start + 2]];
+                            let string = std::str::from_utf8(&data[start + 4..start + len])?;
+                            Ok((
+                                RespValue::VerbatimString {
+                                    encoding,
+                                    data: string.to_string(),
+                                },
+                                &data[start + len + 2..],
+                            ))
+                        }
+                        Ok(len) if data.len() < start + len + 2 => Err(Incomplete.into()),
+                        _ => Err(anyhow::format_err!("invalid verbatim string")),
+                    }
+                } else {
+                    Err(Incomplete.into())
+                }
             }
             b'%' => {
                 // Map: "%<number-of-entries>\r\n<key-1><value-1>...<key-n><value-n>"
-                todo!("map");
+                if let Some(terminator_index) = find_terminator(data) {
+                    if let Ok(num_entries) =
+                        std::str::from_utf8(&data[1..terminator_index])?.parse::<usize>()
+                    {
+                        let mut rest = &data[terminator_index + 2..];
+                        let mut entries = Vec::new();
+                        for _ in 0..num_entries {
+                            let (key, remainder) = deserialize_element(rest)?;
+                            let (value, remainder) = deserialize_element(remainder)?;
+                            entries.push((key, value));
+                            rest = remainder;
+                        }
+                        Ok((RespValue::Map(entries), rest))
+                    } else {
+                        Err(anyhow::format_err!("invalid map"))
+                    }
+                } else {
+                    Err(Incomplete.into())
+                }
             }
             b'~' => {
                 // Set: "~<number-of-elements>\r\n<element-1>...<element-n>"
-                todo!("set");
+                if let Some(terminator_index) = find_terminator(data) {
+                    if let Ok(num_elements) =
+                        std::str::from_utf8(&data[1..terminator_index])?.parse::<usize>()
+                    {
+                        let mut rest = &data[terminator_index + 2..];
+                        let mut elements = Vec::new();
+                        for _ in 0..num_elements {
+                            let result = deserialize_element(rest)?;
+                            elements.push(result.0);
+                            rest = result.1;
+                        }
+                        Ok((RespValue::Set(elements), rest))
+                    } else {
+                        Err(anyhow::format_err!("invalid set"))
+                    }
+                } else {
+                    Err(Incomplete.into())
+                }
             }
             b'>' => {
                 // Push: "><number-of-elements>\r\n<element-1>...<element-n>"
-                todo!("push");
+                if let Some(terminator_index) = find_terminator(data) {
+                    if let Ok(num_elements) =
+                        std::str::from_utf8(&data[1..terminator_index])?.parse::<usize>()
+                    {
+                        let mut rest = &data[terminator_index + 2..];
+                        let mut elements = Vec::new();
+                        for _ in 0..num_elements {
+                            let result = deserialize_element(rest)?;
+                            elements.push(result.0);
+                            rest = result.1;
+                        }
+                        Ok((RespValue::Push(elements), rest))
+                    } else {
+                        Err(anyhow::format_err!("invalid push"))
+                    }
+                } else {
+                    Err(Incomplete.into())
+                }
+            }
+            b'|' => {
+                // Attribute: "|<number-of-entries>\r\n<key-1><value-1>...<key-n><value-n>",
+                // followed by the value it describes. We don't use any attributes, so
+                // they're skipped and the value itself is returned.
+                if let Some(terminator_index) = find_terminator(data) {
+                    if let Some(num_elements) = std::str::from_utf8(&data[1..terminator_index])?
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|n| n.checked_mul(2))
+                    {
+                        let mut rest = &data[terminator_index + 2..];
+                        for _ in 0..num_elements {
+                            rest = deserialize_element(rest)?.1;
+                        }
+                        deserialize_element(rest)
+                    } else {
+                        Err(anyhow::format_err!("invalid attribute"))
+                    }
+                } else {
+                    Err(Incomplete.into())
+                }
             }
             tag => Err(anyhow::format_err!("invalid RESP tag {}", tag)),
         }
     }
 }
 
+/// Deserialize an element of an aggregate, or the value after an attribute. Raw bytes
+/// are only ever sent on their own, so here they're a bulk string whose terminator
+/// hasn't arrived yet.
+fn deserialize_element(data: &[u8]) -> anyhow::Result<(RespValue<'_>, &[u8])> {
+    match RespValue::deserialize(data)? {
+        (RespValue::RawBytes(_), []) => Err(Incomplete.into()),
+        result => Ok(result),
+    }
+}
+
 /// Find `Some(index)` of the first occurence of b'\r\n' in the slice,
 /// or `None` if the slice doesn't contain a terminator.
 fn find_terminator(data: &[u8]) -> Option<usize> {
     let mut i = 0;
-    while i < data.len() - 1 {
+    while i + 1 < data.len() {
         if &data[i..i + 2] == TERMINATOR {
             return Some(i);
         }
@@ -348,7 +588,7 @@ fn find_terminator(data: &[u8]) -> Option<usize> {
 
 #[cfg(test)]
 mod tests {
-    use super::{find_terminator, RespValue};
+    use super::{find_terminator, Incomplete, Protocol, RespValue};
     use bytes::BytesMut;
 
     #[test]
@@ -358,6 +598,30 @@ mod tests {
         assert_eq!(find_terminator(b"\r"), None);
         assert_eq!(find_terminator(b"\n"), None);
         assert_eq!(find_terminator(b"foo"), None);
+        assert_eq!(find_terminator(b""), None);
+    }
+
+    #[test]
+    fn too_short() {
+        assert!(RespValue::deserialize(b"").is_err());
+        for data in [
+            &b"\r"[..],
+            b"\n",
+            b"+",
+            b"$",
+            b"*",
+            b"%",
+            b"~",
+            b">",
+            b"!",
+            b"=",
+        ] {
+            assert!(RespValue::deserialize(data).is_err());
+        }
+
+        // An aggregate that ends early
+        assert!(RespValue::deserialize(b"*2\r\n+a\r\n").is_err());
+        assert!(RespValue::deserialize(b"%1\r\n+key\r\n").is_err());
     }
 
     #[test]
@@ -676,6 +940,241 @@ mod tests {
         }
     }
 
+    #[test]
+    fn map() {
+        {
+            let data = b"%2\r\n+first\r\n:1\r\n+second\r\n*1\r\n$3\r\nfoo\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(
+                value.0,
+                RespValue::Map(vec![
+                    (RespValue::SimpleString("first"), RespValue::Integer(1)),
+                    (
+                        RespValue::SimpleString("second"),
+                        RespValue::Array(vec![RespValue::BulkString("foo")])
+                    ),
+                ])
+            );
+            assert!(value.1.is_empty());
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], data);
+        }
+
+        {
+            // Empty map
+            let data = b"%0\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(value.0, RespValue::Map(vec![]));
+            assert!(value.1.is_empty());
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], data);
+        }
+
+        {
+            // Nested map
+            let data = b"%1\r\n+outer\r\n%1\r\n+inner\r\n#t\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(
+                value.0,
+                RespValue::Map(vec![(
+                    RespValue::SimpleString("outer"),
+                    RespValue::Map(vec![(
+                        RespValue::SimpleString("inner"),
+                        RespValue::Boolean(true)
+                    )])
+                )])
+            );
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], data);
+        }
+
+        {
+            // Unterminated map
+            let data = b"%0";
+            let result = RespValue::deserialize(&data[..]);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn set() {
+        {
+            let data = b"~3\r\n+orange\r\n+apple\r\n:100\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(
+                value.0,
+                RespValue::Set(vec![
+                    RespValue::SimpleString("orange"),
+                    RespValue::SimpleString("apple"),
+                    RespValue::Integer(100),
+                ])
+            );
+            assert!(value.1.is_empty());
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], data);
+        }
+
+        {
+            // Empty set
+            let data = b"~0\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(value.0, RespValue::Set(vec![]));
+            assert!(value.1.is_empty());
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], data);
+        }
+
+        {
+            // Nested sets, and a set inside an array
+            let data = b"*2\r\n~1\r\n~1\r\n$1\r\na\r\n~0\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(
+                value.0,
+                RespValue::Array(vec![
+                    RespValue::Set(vec![RespValue::Set(vec![RespValue::BulkString("a")])]),
+                    RespValue::Set(vec![]),
+                ])
+            );
+            assert!(value.1.is_empty());
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], data);
+        }
+
+        {
+            // Unterminated set
+            let data = b"~0";
+            let result = RespValue::deserialize(&data[..]);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn push() {
+        {
+            let data = b">3\r\n$7\r\nmessage\r\n$7\r\nchannel\r\n$5\r\nhello\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(
+                value.0,
+                RespValue::Push(vec![
+                    RespValue::BulkString("message"),
+                    RespValue::BulkString("channel"),
+                    RespValue::BulkString("hello"),
+                ])
+            );
+            assert!(value.1.is_empty());
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], data);
+
+            // The same as an array, apart from the first byte
+            let RespValue::Push(elements) = value.0 else {
+                unreachable!()
+            };
+            let mut array_buf = BytesMut::new();
+            RespValue::Array(elements).serialize(&mut array_buf);
+            assert_eq!(array_buf[0], b'*');
+            assert_eq!(&array_buf[1..], &buf[1..]);
+        }
+
+        {
+            // Unterminated push
+            let data = b">0";
+            let result = RespValue::deserialize(&data[..]);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn bulk_error() {
+        {
+            let data = b"!21\r\nSYNTAX invalid syntax\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(
+                value.0,
+                RespValue::BulkError("SYNTAX invalid syntax".to_string())
+            );
+            assert!(value.1.is_empty());
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], data);
+        }
+
+        {
+            // Multi-line error
+            let data = b"!21\r\nERR first\r\nERR second\r\n:1\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(
+                value.0,
+                RespValue::BulkError("ERR first\r\nERR second".to_string())
+            );
+            assert_eq!(value.1, b":1\r\n");
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], &data[..data.len() - 4]);
+        }
+
+        {
+            // Shorter than its length
+            let data = b"!10\r\nERR\r\n";
+            let result = RespValue::deserialize(&data[..]);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn verbatim_string() {
+        {
+            let data = b"=15\r\ntxt:Some string\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(
+                value.0,
+                RespValue::VerbatimString {
+                    encoding: *b"txt",
+                    data: "Some string".to_string()
+                }
+            );
+            assert!(value.1.is_empty());
+            let mut buf = BytesMut::new();
+            value.0.serialize(&mut buf);
+            assert_eq!(&buf[..], data);
+        }
+
+        {
+            // The length counts the encoding and colon, and the data can contain
+            // terminators
+            let value = RespValue::VerbatimString {
+                encoding: *b"mkd",
+                data: "# Title\r\n".to_string(),
+            };
+            let mut buf = BytesMut::new();
+            value.serialize(&mut buf);
+            assert_eq!(&buf[..], b"=13\r\nmkd:# Title\r\n\r\n");
+            let (decoded, rest) = RespValue::deserialize(&buf).unwrap();
+            assert_eq!(decoded, value);
+            assert!(rest.is_empty());
+        }
+
+        {
+            // Missing colon after the encoding
+            let data = b"=4\r\ntxt!\r\n";
+            let result = RespValue::deserialize(&data[..]);
+            assert!(result.is_err());
+        }
+
+        {
+            // Shorter than its length
+            let data = b"=15\r\ntxt:Some\r\n";
+            let result = RespValue::deserialize(&data[..]);
+            assert!(result.is_err());
+        }
+    }
+
     #[test]
     fn bulk_string() {
         {
@@ -730,4 +1229,136 @@ mod tests {
             assert_eq!(&buf[..], data);
         }
     }
+
+    #[test]
+    fn truncated_bulk_string() {
+        for data in [&b"$5\r\nhel"[..], b"$5\r\nhello\r", b"$100\r\n"] {
+            let error = RespValue::deserialize(data).unwrap_err();
+            assert!(error.is::<Incomplete>());
+        }
+
+        // Lengths too large to add to the header's are malformed, not incomplete
+        for len in [usize::MAX, usize::MAX - 3] {
+            for ty in ['$', '!', '='] {
+                let data = format!("*1\r\n{ty}{len}\r\nhello\r\n");
+                let error = RespValue::deserialize(data.as_bytes()).unwrap_err();
+                assert!(!error.is::<Incomplete>(), "{data:?}");
+            }
+        }
+
+        // Raw bytes followed by the start of another frame
+        let value = RespValue::deserialize(b"$5\r\nhello+").unwrap();
+        assert_eq!(value.0, RespValue::RawBytes(b"hello"));
+        assert_eq!(value.1, b"+");
+    }
+
+    #[test]
+    fn one_byte_at_a_time() {
+        let frames: [&[u8]; 4] = [
+            b"*2\r\n$4\r\nECHO\r\n$11\r\nhello world\r\n",
+            b"%1\r\n+key\r\n$5\r\nvalue\r\n",
+            b"|1\r\n+a\r\n+b\r\n:10\r\n",
+            b"!5\r\nERR x\r\n",
+        ];
+        for data in frames {
+            for end in 0..data.len() {
+                let error = RespValue::deserialize(&data[..end]).unwrap_err();
+                assert!(error.is::<Incomplete>(), "{:?}", &data[..end]);
+            }
+            let (_, rest) = RespValue::deserialize(data).unwrap();
+            assert!(rest.is_empty());
+        }
+
+        // Malformed frames aren't incomplete
+        for data in [&b"*x\r\n"[..], b":1.5\r\n", b"?\r\n"] {
+            let error = RespValue::deserialize(data).unwrap_err();
+            assert!(!error.is::<Incomplete>());
+        }
+    }
+
+    #[test]
+    fn attribute() {
+        {
+            let data = b"|1\r\n+key-popularity\r\n*2\r\n$1\r\na\r\n,0.1923\r\n+OK\r\n:5\r\n";
+            let value = RespValue::deserialize(&data[..]).unwrap();
+            assert_eq!(value.0, RespValue::SimpleString("OK"));
+            assert_eq!(value.1, b":5\r\n");
+        }
+
+        {
+            // No value after the attribute
+            let data = b"|1\r\n+key\r\n+value\r\n";
+            let result = RespValue::deserialize(&data[..]);
+            assert!(result.is_err());
+        }
+
+        {
+            // Too many entries to count the keys and values
+            let data = b"|9223372036854775808\r\n";
+            let error = RespValue::deserialize(&data[..]).unwrap_err();
+            assert_eq!(error.to_string(), "invalid attribute");
+        }
+    }
+
+    #[test]
+    fn serialize_for_protocol() {
+        let serialized = |value: &RespValue, protocol| {
+            let mut buf = BytesMut::new();
+            value.serialize_for(protocol, &mut buf);
+            buf.to_vec()
+        };
+
+        let value = RespValue::Map(vec![
+            (RespValue::BulkString("a"), RespValue::Boolean(true)),
+            (RespValue::BulkString("b"), RespValue::Double(1.5)),
+            (
+                RespValue::BulkString("c"),
+                RespValue::Set(vec![RespValue::Null, RespValue::NullBulkString]),
+            ),
+        ]);
+        assert_eq!(
+            serialized(&value, Protocol::Resp3),
+            b"%3\r\n$1\r\na\r\n#t\r\n$1\r\nb\r\n,1.5\r\n$1\r\nc\r\n~2\r\n_\r\n_\r\n"
+        );
+        assert_eq!(
+            serialized(&value, Protocol::Resp2),
+            b"*6\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$3\r\n1.5\r\n$1\r\nc\r\n*2\r\n$-1\r\n$-1\r\n"
+        );
+
+        assert_eq!(
+            serialized(
+                &RespValue::BulkError("ERR a\r\nb".to_string()),
+                Protocol::Resp2
+            ),
+            b"-ERR a  b\r\n"
+        );
+        assert_eq!(
+            serialized(
+                &RespValue::VerbatimString {
+                    encoding: *b"txt",
+                    data: "hi".to_string()
+                },
+                Protocol::Resp2
+            ),
+            b"$2\r\nhi\r\n"
+        );
+    }
+
+    #[test]
+    fn owned_bulk_string_array() {
+        let value = {
+            let strings = (1..=3).map(|n| format!("key:{n}")).collect::<Vec<_>>();
+            RespValue::bulk_strings(&strings)
+        };
+        let mut buf = BytesMut::new();
+        value.serialize(&mut buf);
+        assert_eq!(
+            &buf[..],
+            b"*3\r\n$5\r\nkey:1\r\n$5\r\nkey:2\r\n$5\r\nkey:3\r\n"
+        );
+
+        let mut buf = BytesMut::new();
+        RespValue::bulk_strings(Vec::<String>::new()).serialize(&mut buf);
+        assert_eq!(&buf[..], b"*0\r\n");
+    }
 }
@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+
+use crate::store::unix_time_millis;
+
+/// Like Redis, only this many arguments of each command are kept, and each is
+/// truncated to this many bytes.
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+/// Commands that took longer than `slowlog-log-slower-than`, newest first.
+#[derive(Default)]
+pub struct SlowLog {
+    entries: VecDeque<SlowLogEntry>,
+    next_id: u64,
+}
+
+pub struct SlowLogEntry {
+    pub id: u64,
+    pub unix_seconds: u64,
+    pub micros: u64,
+    pub args: Vec<String>,
+}
+
+impl SlowLog {
+    /// Record a slow command, dropping the oldest entries beyond `max_len`.
+    pub fn record(&mut self, micros: u64, args: Vec<String>, max_len: usize) {
+        let mut args = args;
+        if args.len() > MAX_ARGS {
+            let more = args.len() - (MAX_ARGS - 1);
+            args.truncate(MAX_ARGS - 1);
+            args.push(format!("... ({more} more arguments)"));
+        }
+        for arg in args.iter_mut() {
+            if arg.len() > MAX_ARG_LEN {
+                let mut end = MAX_ARG_LEN;
+                while !arg.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let more = arg.len() - end;
+                arg.truncate(end);
+                arg.push_str(&format!("... ({more} more bytes)"));
+            }
+        }
+
+        self.entries.push_front(SlowLogEntry {
+            id: self.next_id,
+            unix_seconds: unix_time_millis() / 1000,
+            micros,
+            args,
+        });
+        self.next_id += 1;
+        self.entries.truncate(max_len);
+    }
+
+    /// The newest `count` entries, or all of them.
+    pub fn get(&self, count: Option<usize>) -> impl Iterator<Item = &SlowLogEntry> {
+        self.entries.iter().take(count.unwrap_or(usize::MAX))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SlowLog;
+
+    #[test]
+    fn record() {
+        let mut slow_log = SlowLog::default();
+        for i in 0..5 {
+            slow_log.record(i, vec![format!("command{i}")], 3);
+        }
+        assert_eq!(slow_log.len(), 3);
+        let ids = slow_log.get(None).map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids, [4, 3, 2]);
+        assert_eq!(slow_log.get(Some(1)).count(), 1);
+
+        slow_log.reset();
+        assert_eq!(slow_log.len(), 0);
+    }
+
+    #[test]
+    fn truncation() {
+        let mut slow_log = SlowLog::default();
+        let args = (0..40).map(|i| i.to_string()).collect();
+        slow_log.record(0, args, 10);
+        let entry = slow_log.get(None).next().unwrap();
+        assert_eq!(entry.args.len(), 32);
+        assert_eq!(entry.args[31], "... (9 more arguments)");
+
+        slow_log.record(0, vec!["a".repeat(130)], 10);
+        let entry = slow_log.get(None).next().unwrap();
+        assert_eq!(
+            entry.args[0],
+            format!("{}... (2 more bytes)", "a".repeat(128))
+        );
+    }
+}
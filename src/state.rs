@@ -1,15 +1,24 @@
+use bytes::BytesMut;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     path::PathBuf,
-    time::{Instant, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
+    command::{Command, COMMANDS},
     config::{Config, ConfigKey},
-    message::{ConfigGetResponse, GetResponse, Message},
-    rdb::read_rdb_file,
-    store::{Store, StoreExpiry, StoreValue},
-    Connection, ConnectionType, REPLICATION_ID,
+    glob::glob_match,
+    latency::LatencyMonitor,
+    message::{
+        ConfigGetResponse, ExpiryOption, GetResponse, Message, ScoreComparison, SetCondition,
+    },
+    random::{choose_distinct, random_index},
+    rdb::{dump, read_rdb_file, reload, restore, write_rdb_file},
+    resp_value::{Protocol, RespValue},
+    slowlog::SlowLog,
+    store::{sorted_by_score, unix_time_millis, Store, StoreData, StoreExpiry, StoreValue},
+    Connection, ConnectionType, DEFAULT_PORT, REPLICATION_ID,
 };
 
 const EMPTY_RDB_FILE: &[u8] = &[
@@ -21,10 +30,34 @@ const EMPTY_RDB_FILE: &[u8] = &[
     0xf0, 0x6e, 0x3b, 0xfe, 0xc0, 0xff, 0x5a, 0xa2,
 ];
 
+const DEFAULT_DATABASES: usize = 16;
+/// The longest string value allowed, unless `proto-max-bulk-len` says otherwise.
+const DEFAULT_PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// The Redis version we report, which matches the one in `EMPTY_RDB_FILE`.
+const REDIS_VERSION: &str = "7.2.0";
+
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
 pub struct State {
-    store: Store,
+    databases: Vec<Store>,
     config: Config,
     role_state: RoleState,
+    /// Writes that changed the store, waiting to be propagated to replicas.
+    propagated: Vec<Message>,
+    /// The database replicas will apply propagated writes to, if known.
+    propagated_db: Option<usize>,
+    client_pause: Option<ClientPause>,
+    latency: LatencyMonitor,
+    slow_log: SlowLog,
+    /// Lines waiting to be sent to each `MONITOR` connection.
+    monitors: HashMap<usize, VecDeque<String>>,
+    next_monitor_id: usize,
+}
+
+/// A `CLIENT PAUSE` holding back client commands until `until`.
+struct ClientPause {
+    until: Instant,
+    writes_only: bool,
 }
 
 enum RoleState {
@@ -54,10 +87,26 @@ enum HandshakeState {
     Complete,
 }
 
+/// A `WAIT` that's waiting for enough replicas to acknowledge an offset.
+#[derive(Debug)]
+pub struct PendingWait {
+    offset: usize,
+    num_replicas: usize,
+    deadline: Option<Instant>,
+}
+
 struct MasterState {
     replication_id: String,
     replication_offset: isize,
-    num_replicas: usize,
+    /// Last offset acknowledged by each connected replica, keyed by replica id.
+    replica_offsets: HashMap<usize, usize>,
+    next_replica_id: usize,
+    /// The replication offset just after the last propagated write. Unlike
+    /// `replication_offset`, this doesn't count `GETACK` requests.
+    write_offset: usize,
+    /// The write offset when we last sent replicas a `GETACK`, so waiters on the same
+    /// writes can share a single round of acks.
+    getack_offset: Option<usize>,
 }
 
 impl Default for MasterState {
@@ -65,30 +114,43 @@ impl Default for MasterState {
         MasterState {
             replication_id: REPLICATION_ID.into(),
             replication_offset: 0,
-            num_replicas: 0,
+            replica_offsets: HashMap::new(),
+            next_replica_id: 0,
+            write_offset: 0,
+            getack_offset: None,
         }
     }
 }
 
+impl MasterState {
+    /// The number of replicas that have acknowledged at least `offset`.
+    fn acked_replicas(&self, offset: usize) -> usize {
+        self.replica_offsets
+            .values()
+            .filter(|acked| **acked >= offset)
+            .count()
+    }
+}
+
 impl State {
     pub fn new(config: Config) -> anyhow::Result<Self> {
-        let store = if config.0.contains_key(&ConfigKey::Dir)
-            && config.0.contains_key(&ConfigKey::DbFilename)
-        {
-            let path = {
-                let mut p = PathBuf::new();
-                p.push(config.0.get(&ConfigKey::Dir).unwrap()[0].clone());
-                p.push(config.0.get(&ConfigKey::DbFilename).unwrap()[0].clone());
-                p
-            };
-            if path.exists() {
-                read_rdb_file(path)?
-            } else {
-                eprintln!("warning: database file {:?} doesn't exist", path);
-                Store::default()
+        let database_count = match config.0.get(&ConfigKey::Databases) {
+            Some(values) => match values[0].parse::<usize>() {
+                Ok(count) if count > 0 => count,
+                _ => anyhow::bail!("invalid databases count {:?}", values[0]),
+            },
+            None => DEFAULT_DATABASES,
+        };
+        let databases = match rdb_path(&config) {
+            Some(path) if path.exists() => read_rdb_file(path, database_count)?,
+            path => {
+                if let Some(path) = path {
+                    eprintln!("warning: database file {:?} doesn't exist", path);
+                }
+                let mut databases = Vec::new();
+                databases.resize_with(database_count, Store::default);
+                databases
             }
-        } else {
-            Store::default()
         };
 
         let role_state = if config.0.contains_key(&ConfigKey::ReplicaOf) {
@@ -98,12 +160,28 @@ impl State {
         };
 
         Ok(State {
-            store,
+            databases,
             config,
             role_state,
+            propagated: Vec::new(),
+            propagated_db: Some(0),
+            client_pause: None,
+            latency: LatencyMonitor::default(),
+            slow_log: SlowLog::default(),
+            monitors: HashMap::new(),
+            next_monitor_id: 0,
         })
     }
 
+    /// Write every database to the configured RDB file. Does nothing if no file is
+    /// configured.
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(path) = rdb_path(&self.config) {
+            write_rdb_file(&self.databases, path)?;
+        }
+        Ok(())
+    }
+
     pub fn is_master(&self) -> bool {
         matches!(self.role_state, RoleState::Master(_))
     }
@@ -116,6 +194,13 @@ impl State {
         &mut self,
         connection: &mut Connection,
     ) -> anyhow::Result<Option<Message>> {
+        if let Some(line) = connection
+            .monitor_id
+            .and_then(|id| self.monitors.get_mut(&id)?.pop_front())
+        {
+            return Ok(Some(Message::Reply(RespValue::OwnedSimpleString(line))));
+        }
+
         Ok(match &mut self.role_state {
             RoleState::Slave(slave_state) => {
                 if matches!(connection.ty, ConnectionType::Master) {
@@ -151,10 +236,20 @@ impl State {
                     None
                 }
             }
-            RoleState::Master(_) => {
+            RoleState::Master(master_state) => {
                 if matches!(connection.ty, ConnectionType::Slave) && connection.send_rdb {
                     connection.send_rdb = false;
                     Some(Message::DatabaseFile(EMPTY_RDB_FILE.to_vec()))
+                } else if let Some(wait) = &connection.pending_wait {
+                    let num_replicas = master_state.acked_replicas(wait.offset);
+                    if num_replicas >= wait.num_replicas
+                        || wait.deadline.is_some_and(|d| Instant::now() >= d)
+                    {
+                        connection.pending_wait = None;
+                        Some(Message::WaitReply { num_replicas })
+                    } else {
+                        None
+                    }
                 } else {
                     None
                 }
@@ -162,14 +257,267 @@ impl State {
         })
     }
 
+    /// How much longer `message` has to wait before it can be handled, if it's held
+    /// back by a `CLIENT PAUSE`. Only commands from clients are paused, and `CLIENT`
+    /// commands always go through so the pause can be lifted.
+    pub fn pause_remaining(&self, message: &Message, connection: &Connection) -> Option<Duration> {
+        let pause = self.client_pause.as_ref()?;
+        if !matches!(connection.ty, ConnectionType::Client)
+            || matches!(
+                message,
+                Message::ClientPause { .. } | Message::ClientUnpause
+            )
+            || (pause.writes_only && !Command::of(message).is_some_and(|c| c.propagate))
+        {
+            return None;
+        }
+        let remaining = pause.until.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    /// Handle `message`, which was parsed from `request`. The raw request is kept so
+    /// the slow log and `MONITOR` can show exactly what the client sent.
     pub fn handle_incoming(
+        &mut self,
+        message: &Message,
+        request: &[u8],
+        connection: &mut Connection,
+    ) -> anyhow::Result<Option<Message>> {
+        let start = Instant::now();
+        let response = self.dispatch(message, connection);
+        // Time spent in `DEBUG SLEEP` still counts, even though it's spent elsewhere
+        let elapsed = match message {
+            Message::DebugSleep(duration) => start.elapsed().saturating_add(*duration),
+            _ => start.elapsed(),
+        };
+        let millis = elapsed.as_millis() as u64;
+        if self
+            .latency_threshold()
+            .is_some_and(|threshold| millis >= threshold)
+        {
+            self.latency.record("command", millis);
+        }
+        let micros = elapsed.as_micros() as u64;
+        if self
+            .slowlog_threshold()
+            .is_some_and(|threshold| micros >= threshold)
+        {
+            let max_len = self.config_value(ConfigKey::SlowlogMaxLen).unwrap_or(128);
+            self.slow_log
+                .record(micros, Message::request_args(request), max_len);
+        }
+        if !self.monitors.is_empty() && !matches!(message, Message::Monitor) {
+            self.feed_monitors(request, connection);
+        }
+        response
+    }
+
+    /// Queue `message` for every `MONITOR` connection, formatted like Redis does.
+    fn feed_monitors(&mut self, request: &[u8], connection: &Connection) {
+        let args = Message::request_args(request);
+        if args.is_empty() {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let addr = match connection.addr {
+            Some(addr) => addr.to_string(),
+            None => "unknown".to_string(),
+        };
+        let mut line = format!(
+            "{}.{:06} [{} {}]",
+            now.as_secs(),
+            now.subsec_micros(),
+            connection.db,
+            addr
+        );
+        for arg in &args {
+            line.push(' ');
+            line.push_str(&quote(arg));
+        }
+        for lines in self.monitors.values_mut() {
+            lines.push_back(line.clone());
+        }
+    }
+
+    pub fn remove_monitor(&mut self, monitor_id: usize) {
+        self.monitors.remove(&monitor_id);
+    }
+
+    /// The `slowlog-log-slower-than` in microseconds, or `None` if the slow log
+    /// is disabled.
+    fn slowlog_threshold(&self) -> Option<u64> {
+        match self.config.0.get(&ConfigKey::SlowlogLogSlowerThan) {
+            Some(threshold) => threshold[0].parse::<u64>().ok(),
+            None => Some(10_000),
+        }
+    }
+
+    fn config_value<T: std::str::FromStr>(&self, key: ConfigKey) -> Option<T> {
+        self.config.0.get(&key)?[0].parse().ok()
+    }
+
+    /// The `latency-monitor-threshold` in milliseconds, or `None` if latency
+    /// monitoring is disabled.
+    fn latency_threshold(&self) -> Option<u64> {
+        let threshold = self.config.0.get(&ConfigKey::LatencyMonitorThreshold)?;
+        threshold[0].parse::<u64>().ok().filter(|t| *t > 0)
+    }
+
+    fn dispatch(
         &mut self,
         message: &Message,
         connection: &mut Connection,
     ) -> anyhow::Result<Option<Message>> {
         match message {
             Message::Echo(message) => Ok(Some(Message::Echo(message.to_owned()))),
-            Message::CommandDocs => Ok(Some(Message::CommandDocs)),
+            // There's no documentation to give, but clients only need the names. The
+            // map is sent as a flat array of names and details to RESP2 clients.
+            Message::CommandDocs => Ok(Some(Message::Reply(RespValue::Map(
+                COMMANDS
+                    .iter()
+                    .map(|command| {
+                        (
+                            RespValue::OwnedBulkString(command.name.to_lowercase()),
+                            RespValue::Map(vec![]),
+                        )
+                    })
+                    .collect(),
+            )))),
+            Message::CommandCount => Ok(Some(Message::Reply(RespValue::Integer(
+                COMMANDS.len() as i64
+            )))),
+            Message::InfoRequest { sections } => Ok(Some(self.info(sections))),
+            // The connection loop does the sleeping, without holding the state lock
+            Message::DebugSleep(_) => Ok(Some(Message::Ok)),
+            Message::DebugReload => {
+                let reloaded = self.save().and_then(|_| reload(&self.databases));
+                match reloaded {
+                    Ok(databases) => {
+                        self.databases = databases;
+                        Ok(Some(Message::Ok))
+                    }
+                    // A bulk error is sent as a simple error to RESP2 clients, and
+                    // unlike one it can hold a message that isn't known up front
+                    Err(e) => Ok(Some(Message::Reply(RespValue::BulkError(format!(
+                        "ERR Error trying to save the DB: {}",
+                        e
+                    ))))),
+                }
+            }
+            Message::LatencyLatest => Ok(Some(Message::Reply(RespValue::Array(
+                self.latency
+                    .latest()
+                    .into_iter()
+                    .map(|spike| {
+                        RespValue::Array(vec![
+                            RespValue::OwnedBulkString(spike.event.to_string()),
+                            RespValue::Integer(spike.unix_seconds as i64),
+                            RespValue::Integer(spike.millis as i64),
+                            RespValue::Integer(spike.max_millis as i64),
+                        ])
+                    })
+                    .collect(),
+            )))),
+            Message::LatencyHistory { event } => Ok(Some(Message::Reply(RespValue::Array(
+                self.latency
+                    .history(event)
+                    .into_iter()
+                    .map(|(unix_seconds, millis)| {
+                        RespValue::Array(vec![
+                            RespValue::Integer(unix_seconds as i64),
+                            RespValue::Integer(millis as i64),
+                        ])
+                    })
+                    .collect(),
+            )))),
+            Message::LatencyReset { events } => Ok(Some(Message::Reply(RespValue::Integer(
+                self.latency.reset(events) as i64,
+            )))),
+            Message::SlowLogGet { count } => Ok(Some(Message::Reply(RespValue::Array(
+                self.slow_log
+                    .get(*count)
+                    .map(|entry| {
+                        RespValue::Array(vec![
+                            RespValue::Integer(entry.id as i64),
+                            RespValue::Integer(entry.unix_seconds as i64),
+                            RespValue::Integer(entry.micros as i64),
+                            RespValue::bulk_strings(entry.args.iter().cloned()),
+                            // Client address and name, which aren't tracked
+                            RespValue::BulkString(""),
+                            RespValue::BulkString(""),
+                        ])
+                    })
+                    .collect(),
+            )))),
+            Message::SlowLogLen => Ok(Some(Message::Reply(RespValue::Integer(
+                self.slow_log.len() as i64,
+            )))),
+            Message::Hello { protover } => {
+                match protover {
+                    Some(2) => connection.protocol = Protocol::Resp2,
+                    Some(3) => connection.protocol = Protocol::Resp3,
+                    Some(_) => {
+                        return Ok(Some(Message::Reply(RespValue::SimpleError(
+                            "NOPROTO unsupported protocol version",
+                        ))))
+                    }
+                    None => {}
+                }
+                let proto = match connection.protocol {
+                    Protocol::Resp2 => 2,
+                    Protocol::Resp3 => 3,
+                };
+                let role = if self.is_master() {
+                    "master"
+                } else {
+                    "replica"
+                };
+                Ok(Some(Message::Reply(RespValue::Map(vec![
+                    (
+                        RespValue::BulkString("server"),
+                        RespValue::BulkString("redis"),
+                    ),
+                    (
+                        RespValue::BulkString("version"),
+                        RespValue::BulkString(REDIS_VERSION),
+                    ),
+                    (RespValue::BulkString("proto"), RespValue::Integer(proto)),
+                    (
+                        RespValue::BulkString("mode"),
+                        RespValue::BulkString("standalone"),
+                    ),
+                    (RespValue::BulkString("role"), RespValue::BulkString(role)),
+                    (RespValue::BulkString("modules"), RespValue::Array(vec![])),
+                ]))))
+            }
+            Message::Monitor => {
+                if connection.monitor_id.is_none() {
+                    connection.monitor_id = Some(self.next_monitor_id);
+                    self.monitors.insert(self.next_monitor_id, VecDeque::new());
+                    self.next_monitor_id += 1;
+                }
+                Ok(Some(Message::Ok))
+            }
+            Message::SlowLogReset => {
+                self.slow_log.reset();
+                Ok(Some(Message::Ok))
+            }
+            Message::ClientPause {
+                timeout,
+                writes_only,
+            } => {
+                self.client_pause = Some(ClientPause {
+                    until: Instant::now() + *timeout,
+                    writes_only: *writes_only,
+                });
+                Ok(Some(Message::Ok))
+            }
+            Message::ClientUnpause => {
+                self.client_pause = None;
+                Ok(Some(Message::Ok))
+            }
             Message::ConfigGetRequest { key } => match self.config.0.get(key) {
                 Some(values) => Ok(Some(Message::ConfigGetResponse(Some(ConfigGetResponse {
                     key: *key,
@@ -177,58 +525,980 @@ impl State {
                 })))),
                 None => Ok(Some(Message::ConfigGetResponse(None))),
             },
-            Message::KeysRequest => {
-                let keys = self.store.data.keys().cloned().collect();
+            Message::ConfigSetRequest { key, values } => {
+                self.config.0.insert(*key, values.clone());
+                self.propagate(connection.db, message.clone());
+                Ok(Some(Message::Ok))
+            }
+            Message::KeysRequest { pattern } => {
+                let keys = self
+                    .iter_live_keys(connection.db)
+                    .filter(|(key, _)| glob_match(pattern, key))
+                    .map(|(key, _)| key.clone())
+                    .collect();
                 Ok(Some(Message::KeysResponse { keys }))
             }
-            Message::GetRequest { key } => match self.store.data.get(key) {
-                Some(value) => {
-                    match value.expiry {
-                        Some(StoreExpiry::Duration(d)) => {
-                            if Instant::now() > value.updated + d {
-                                // Key has expired
-                                Ok(Some(Message::GetResponse(GetResponse::NotFound)))
-                            } else {
-                                Ok(Some(Message::GetResponse(GetResponse::Found(
-                                    value.data.clone(),
-                                ))))
+            Message::Scan {
+                cursor,
+                pattern,
+                count,
+                type_name,
+            } => {
+                let (next, keys) = self.databases[connection.db].scan(*cursor, count.unwrap_or(10));
+                let mut matched = Vec::new();
+                for key in keys {
+                    // Only the keys in this batch are checked for expiry
+                    self.expire_if_stale(connection.db, &key);
+                    let Some(value) = self.databases[connection.db].get(&key) else {
+                        continue;
+                    };
+                    if pattern.as_ref().is_some_and(|p| !glob_match(p, &key))
+                        || type_name
+                            .as_ref()
+                            .is_some_and(|t| !t.eq_ignore_ascii_case(value.type_name()))
+                    {
+                        continue;
+                    }
+                    matched.push(key);
+                }
+                Ok(Some(Message::Reply(RespValue::Array(vec![
+                    RespValue::OwnedBulkString(next.to_string()),
+                    RespValue::bulk_strings(matched),
+                ]))))
+            }
+            Message::DbSize => Ok(Some(Message::Reply(RespValue::Integer(
+                self.iter_live_keys(connection.db).count() as i64,
+            )))),
+            Message::RandomKey => {
+                let keys = self.iter_live_keys(connection.db).collect::<Vec<_>>();
+                Ok(Some(Message::Reply(if keys.is_empty() {
+                    RespValue::NullBulkString
+                } else {
+                    RespValue::OwnedBulkString(keys[random_index(keys.len())].0.clone())
+                })))
+            }
+            Message::GetRequest { key } => {
+                self.expire_if_stale(connection.db, key);
+                match self.databases[connection.db].get(key) {
+                    Some(value) => match &value.data {
+                        StoreData::String(data) => {
+                            Ok(Some(Message::GetResponse(GetResponse::Found(data.clone()))))
+                        }
+                        _ => Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    },
+                    _ => Ok(Some(Message::GetResponse(GetResponse::NotFound))),
+                }
+            }
+            Message::GetEx { key, expiry_option } => {
+                let (data, effect) = match self.databases[connection.db].get_mut(key) {
+                    Some(value) => {
+                        let StoreData::String(data) = &value.data else {
+                            return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE))));
+                        };
+                        let data = data.clone();
+                        // Without an option this is a pure read, so nothing is propagated
+                        let effect = match expiry_option {
+                            Some(ExpiryOption::Set(expiry)) => {
+                                value.set_expiry(Some(*expiry));
+                                let unix_millis = match value.expiry {
+                                    Some(StoreExpiry::UnixTimestampMillis(t)) => t as i64,
+                                    _ => unreachable!("set_expiry stores absolute expiries"),
+                                };
+                                Some(Message::PExpireAt {
+                                    key: key.clone(),
+                                    unix_millis,
+                                })
                             }
+                            Some(ExpiryOption::Persist) if value.expiry.is_some() => {
+                                value.set_expiry(None);
+                                Some(Message::Persist { key: key.clone() })
+                            }
+                            _ => None,
+                        };
+                        (Some(data), effect)
+                    }
+                    None => (None, None),
+                };
+                if let Some(effect) = effect {
+                    self.propagate(connection.db, effect);
+                }
+                Ok(Some(Message::GetResponse(match data {
+                    Some(data) => GetResponse::Found(data),
+                    None => GetResponse::NotFound,
+                })))
+            }
+            Message::Exists { keys } => {
+                let count = keys
+                    .iter()
+                    .filter(|key| self.databases[connection.db].get(key).is_some())
+                    .count();
+                Ok(Some(Message::Reply(RespValue::Integer(count as i64))))
+            }
+            Message::ObjectEncoding { key } => match self.databases[connection.db].get(key) {
+                Some(value) => Ok(Some(Message::Reply(RespValue::BulkString(
+                    value.encoding(),
+                )))),
+                _ => Ok(Some(Message::Reply(RespValue::SimpleError(
+                    "ERR no such key",
+                )))),
+            },
+            Message::Select { index } => {
+                let response = match usize::try_from(*index) {
+                    Ok(index) if index < self.databases.len() => {
+                        connection.db = index;
+                        Message::Ok
+                    }
+                    _ => Message::Reply(RespValue::SimpleError("ERR DB index is out of range")),
+                };
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(response))
+                }
+            }
+            Message::FlushDb | Message::FlushAll => {
+                if matches!(message, Message::FlushDb) {
+                    self.databases[connection.db].clear();
+                } else {
+                    self.databases.iter_mut().for_each(Store::clear);
+                }
+                self.propagate(connection.db, message.clone());
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Ok))
+                }
+            }
+            Message::Touch { keys } => {
+                let now = Instant::now();
+                let mut count = 0;
+                for key in keys {
+                    if let Some(value) = self.databases[connection.db].get_mut(key) {
+                        value.accessed = now;
+                        count += 1;
+                    }
+                }
+                Ok(Some(Message::Reply(RespValue::Integer(count))))
+            }
+            // Removal is synchronous for both, for now
+            Message::Del { keys } | Message::Unlink { keys } => {
+                let mut count = 0;
+                for key in keys {
+                    if self.databases[connection.db].remove(key).is_some() {
+                        count += 1;
+                    }
+                }
+                if count > 0 {
+                    self.propagate(connection.db, message.clone());
+                }
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(count))))
+                }
+            }
+            Message::Type { key } => {
+                let type_name = match self.databases[connection.db].get(key) {
+                    Some(value) => value.type_name(),
+                    None => "none",
+                };
+                Ok(Some(Message::Reply(RespValue::SimpleString(type_name))))
+            }
+            Message::Expire { key, seconds } => {
+                let unix_millis =
+                    (unix_time_millis() as i64).saturating_add(seconds.saturating_mul(1000));
+                Ok(self.expire_at(key, unix_millis, connection))
+            }
+            Message::PExpire { key, millis } => {
+                let unix_millis = (unix_time_millis() as i64).saturating_add(*millis);
+                Ok(self.expire_at(key, unix_millis, connection))
+            }
+            Message::ExpireAt { key, unix_seconds } => {
+                Ok(self.expire_at(key, unix_seconds.saturating_mul(1000), connection))
+            }
+            Message::PExpireAt { key, unix_millis } => {
+                Ok(self.expire_at(key, *unix_millis, connection))
+            }
+            Message::Persist { key } => {
+                let persisted = match self.databases[connection.db].get_mut(key) {
+                    Some(value) if value.expiry.is_some() => {
+                        value.set_expiry(None);
+                        true
+                    }
+                    _ => false,
+                };
+                if persisted {
+                    self.propagate(connection.db, Message::Persist { key: key.clone() });
+                }
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(persisted as i64))))
+                }
+            }
+            Message::Rename { src, dst } | Message::RenameNx { src, dst } => {
+                let only_if_new = matches!(message, Message::RenameNx { .. });
+                let store = &mut self.databases[connection.db];
+                let response = if store.get(src).is_none() {
+                    Message::Reply(RespValue::SimpleError("ERR no such key"))
+                } else if only_if_new && store.get(dst).is_some() {
+                    Message::Reply(RespValue::Integer(0))
+                } else {
+                    let value = store.remove(src).unwrap();
+                    let expiry = value.expiry_from(Instant::now());
+                    store.set_with_expiry(dst.clone(), value.data, expiry);
+                    self.propagate(connection.db, message.clone());
+                    if only_if_new {
+                        Message::Reply(RespValue::Integer(1))
+                    } else {
+                        Message::Ok
+                    }
+                };
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(response))
+                }
+            }
+            Message::Copy {
+                src,
+                dst,
+                db,
+                replace,
+            } => {
+                let dst_db = match db.map_or(Ok(connection.db), usize::try_from) {
+                    Ok(dst_db) if dst_db < self.databases.len() => dst_db,
+                    _ => {
+                        return Ok(Some(Message::Reply(RespValue::SimpleError(
+                            "ERR DB index is out of range",
+                        ))))
+                    }
+                };
+                if src == dst && dst_db == connection.db {
+                    return Ok(Some(Message::Reply(RespValue::SimpleError(
+                        "ERR source and destination objects are the same",
+                    ))));
+                }
+                let copied = match self.databases[connection.db].get(src) {
+                    Some(value) if *replace || self.databases[dst_db].get(dst).is_none() => {
+                        let data = value.data.clone();
+                        let expiry = value.expiry_from(Instant::now());
+                        self.databases[dst_db].set_with_expiry(dst.clone(), data, expiry);
+                        self.propagate(connection.db, message.clone());
+                        true
+                    }
+                    _ => false,
+                };
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(copied as i64))))
+                }
+            }
+            Message::Dump { key } => Ok(Some(Message::Reply(
+                match self.databases[connection.db].get(key) {
+                    Some(value) => RespValue::OwnedBulkString(dump(&value.data)),
+                    None => RespValue::NullBulkString,
+                },
+            ))),
+            Message::Restore {
+                key,
+                ttl,
+                payload,
+                replace,
+                absolute_ttl,
+            } => {
+                let response = if !replace && self.databases[connection.db].get(key).is_some() {
+                    Message::Reply(RespValue::SimpleError(
+                        "BUSYKEY Target key name already exists.",
+                    ))
+                } else if let Ok(data) = restore(payload) {
+                    let expiry = match (*ttl, *absolute_ttl) {
+                        (0, _) => None,
+                        (ttl, true) => Some(StoreExpiry::UnixTimestampMillis(ttl)),
+                        (ttl, false) => Some(StoreExpiry::Duration(Duration::from_millis(ttl))),
+                    };
+                    self.databases[connection.db].set_with_expiry(key.clone(), data, expiry);
+                    self.propagate(connection.db, message.clone());
+                    Message::Ok
+                } else {
+                    Message::Reply(RespValue::SimpleError(
+                        "ERR DUMP payload version or checksum are wrong",
+                    ))
+                };
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(response))
+                }
+            }
+            Message::Ttl { key } => {
+                let ttl = match self.databases[connection.db].get(key) {
+                    Some(value) => match value.ttl_millis() {
+                        Some(millis) => (millis + 500) / 1000,
+                        None => -1,
+                    },
+                    None => -2,
+                };
+                Ok(Some(Message::Reply(RespValue::Integer(ttl))))
+            }
+            Message::PTtl { key } => {
+                let ttl = match self.databases[connection.db].get(key) {
+                    Some(value) => value.ttl_millis().unwrap_or(-1),
+                    None => -2,
+                };
+                Ok(Some(Message::Reply(RespValue::Integer(ttl))))
+            }
+            Message::Incr { key } => {
+                let existing = self.databases[connection.db].get(key);
+                let n = match existing.map(|v| &v.data) {
+                    Some(StoreData::String(data)) => match data.parse::<i64>() {
+                        Ok(n) => n,
+                        Err(_) => {
+                            return Ok(Some(Message::Reply(RespValue::SimpleError(
+                                "ERR value is not an integer or out of range",
+                            ))))
                         }
-                        Some(StoreExpiry::UnixTimestampMillis(t)) => {
-                            let unix_time =
-                                SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
-                            if t < unix_time {
-                                // Key has expired
-                                Ok(Some(Message::GetResponse(GetResponse::NotFound)))
-                            } else {
-                                Ok(Some(Message::GetResponse(GetResponse::Found(
-                                    value.data.clone(),
-                                ))))
+                    },
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => 0,
+                };
+                let Some(n) = n.checked_add(1) else {
+                    return Ok(Some(Message::Reply(RespValue::SimpleError(
+                        "ERR increment or decrement would overflow",
+                    ))));
+                };
+                let expiry = existing.and_then(|v| v.expiry_from(Instant::now()));
+                self.databases[connection.db].set_with_expiry(
+                    key.clone(),
+                    StoreData::String(n.to_string()),
+                    expiry,
+                );
+                // Propagate the resulting value rather than the increment, so replaying
+                // the replication stream always reproduces the same state
+                self.propagate(
+                    connection.db,
+                    Message::Set {
+                        key: key.clone(),
+                        value: n.to_string(),
+                        expiry: None,
+                        keep_ttl: true,
+                        condition: None,
+                        return_old: false,
+                    },
+                );
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(n))))
+                }
+            }
+            Message::Append { key, value } => {
+                let length = match self.databases[connection.db].get_mut(key) {
+                    Some(existing) => match &mut existing.data {
+                        StoreData::String(data) => {
+                            data.push_str(value);
+                            data.len()
+                        }
+                        _ => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    },
+                    None => {
+                        self.databases[connection.db]
+                            .set(key.clone(), StoreData::String(value.clone()));
+                        value.len()
+                    }
+                };
+                self.propagate(connection.db, message.clone());
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(length as i64))))
+                }
+            }
+            Message::LPush { key, values } | Message::RPush { key, values } => {
+                let value = self.databases[connection.db]
+                    .get_or_set_with(key, || StoreData::List(VecDeque::new()));
+                let StoreData::List(list) = &mut value.data else {
+                    return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE))));
+                };
+                for v in values {
+                    if matches!(message, Message::LPush { .. }) {
+                        list.push_front(v.clone());
+                    } else {
+                        list.push_back(v.clone());
+                    }
+                }
+                let length = list.len();
+                self.propagate(connection.db, message.clone());
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(length as i64))))
+                }
+            }
+            Message::LPop { key, count } | Message::RPop { key, count } => {
+                let store = &mut self.databases[connection.db];
+                let list = match store.get_mut(key) {
+                    Some(StoreValue {
+                        data: StoreData::List(list),
+                        ..
+                    }) => list,
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None if count.is_some() => {
+                        return Ok(Some(Message::Reply(RespValue::NullArray)))
+                    }
+                    None => return Ok(Some(Message::GetResponse(GetResponse::NotFound))),
+                };
+                let mut popped = Vec::new();
+                while popped.len() < count.unwrap_or(1) {
+                    let value = if matches!(message, Message::LPop { .. }) {
+                        list.pop_front()
+                    } else {
+                        list.pop_back()
+                    };
+                    match value {
+                        Some(value) => popped.push(value),
+                        None => break,
+                    }
+                }
+                // Empty lists don't exist
+                if list.is_empty() {
+                    store.remove(key);
+                }
+                if !popped.is_empty() {
+                    self.propagate(connection.db, message.clone());
+                }
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else if count.is_some() {
+                    Ok(Some(Message::Reply(RespValue::bulk_strings(popped))))
+                } else {
+                    Ok(Some(Message::GetResponse(match popped.pop() {
+                        Some(value) => GetResponse::Found(value),
+                        None => GetResponse::NotFound,
+                    })))
+                }
+            }
+            Message::LSet { key, index, value } => {
+                let list = match self.databases[connection.db].get_mut(key) {
+                    Some(StoreValue {
+                        data: StoreData::List(list),
+                        ..
+                    }) => list,
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => {
+                        return Ok(Some(Message::Reply(RespValue::SimpleError(
+                            "ERR no such key",
+                        ))))
+                    }
+                };
+                // Negative indices count back from the end of the list
+                let index = if *index < 0 {
+                    list.len() as i64 + index
+                } else {
+                    *index
+                };
+                let Some(element) = usize::try_from(index).ok().and_then(|i| list.get_mut(i))
+                else {
+                    return Ok(Some(Message::Reply(RespValue::SimpleError(
+                        "ERR index out of range",
+                    ))));
+                };
+                *element = value.clone();
+                self.propagate(connection.db, message.clone());
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Ok))
+                }
+            }
+            Message::SAdd { key, members } => {
+                let value = self.databases[connection.db]
+                    .get_or_set_with(key, || StoreData::Set(HashSet::new()));
+                let StoreData::Set(set) = &mut value.data else {
+                    return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE))));
+                };
+                let added = members.iter().filter(|m| set.insert((*m).clone())).count();
+                if added > 0 {
+                    self.propagate(connection.db, message.clone());
+                }
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(added as i64))))
+                }
+            }
+            Message::SRem { key, members } => {
+                let store = &mut self.databases[connection.db];
+                let set = match store.get_mut(key) {
+                    Some(StoreValue {
+                        data: StoreData::Set(set),
+                        ..
+                    }) => set,
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => return Ok(Some(Message::Reply(RespValue::Integer(0)))),
+                };
+                let removed = members.iter().filter(|m| set.remove(*m)).count();
+                // Empty sets don't exist
+                if set.is_empty() {
+                    store.remove(key);
+                }
+                if removed > 0 {
+                    self.propagate(connection.db, message.clone());
+                }
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(removed as i64))))
+                }
+            }
+            Message::SMembers { key } | Message::SCard { key } => {
+                let members = match self.databases[connection.db].get(key).map(|v| &v.data) {
+                    Some(StoreData::Set(set)) => set.iter().collect(),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => Vec::new(),
+                };
+                if matches!(message, Message::SCard { .. }) {
+                    return Ok(Some(Message::Reply(RespValue::Integer(
+                        members.len() as i64
+                    ))));
+                }
+                Ok(Some(Message::Reply(RespValue::bulk_strings(members))))
+            }
+            Message::HSet { key, fields } => {
+                let value = self.databases[connection.db]
+                    .get_or_set_with(key, || StoreData::Hash(HashMap::new()));
+                let StoreData::Hash(hash) = &mut value.data else {
+                    return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE))));
+                };
+                let added = fields
+                    .iter()
+                    .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+                    .count();
+                self.propagate(connection.db, message.clone());
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(added as i64))))
+                }
+            }
+            Message::HDel { key, fields } => {
+                let store = &mut self.databases[connection.db];
+                let hash = match store.get_mut(key) {
+                    Some(StoreValue {
+                        data: StoreData::Hash(hash),
+                        ..
+                    }) => hash,
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => return Ok(Some(Message::Reply(RespValue::Integer(0)))),
+                };
+                let removed = fields.iter().filter(|f| hash.remove(*f).is_some()).count();
+                // Empty hashes don't exist
+                if hash.is_empty() {
+                    store.remove(key);
+                }
+                if removed > 0 {
+                    self.propagate(connection.db, message.clone());
+                }
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(removed as i64))))
+                }
+            }
+            Message::HMGet { key, fields } => {
+                let hash = match self.databases[connection.db].get(key).map(|v| &v.data) {
+                    Some(StoreData::Hash(hash)) => Some(hash),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => None,
+                };
+                Ok(Some(Message::Reply(RespValue::Array(
+                    fields
+                        .iter()
+                        .map(|f| match hash.and_then(|h| h.get(f)) {
+                            Some(value) => RespValue::OwnedBulkString(value.clone()),
+                            None => RespValue::NullBulkString,
+                        })
+                        .collect(),
+                ))))
+            }
+            Message::HLen { key } | Message::HExists { key, .. } => {
+                let hash = match self.databases[connection.db].get(key).map(|v| &v.data) {
+                    Some(StoreData::Hash(hash)) => Some(hash),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => None,
+                };
+                let n = match message {
+                    Message::HExists { field, .. } => {
+                        hash.is_some_and(|h| h.contains_key(field)) as usize
+                    }
+                    _ => hash.map_or(0, |h| h.len()),
+                };
+                Ok(Some(Message::Reply(RespValue::Integer(n as i64))))
+            }
+            Message::HGetAll { key } | Message::HKeys { key } | Message::HVals { key } => {
+                let hash = match self.databases[connection.db].get(key).map(|v| &v.data) {
+                    Some(StoreData::Hash(hash)) => hash.iter().collect(),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => Vec::new(),
+                };
+                let elements = hash.into_iter().flat_map(|(field, value)| match message {
+                    Message::HGetAll { .. } => vec![field, value],
+                    Message::HKeys { .. } => vec![field],
+                    _ => vec![value],
+                });
+                Ok(Some(Message::Reply(RespValue::bulk_strings(elements))))
+            }
+            Message::ZAdd {
+                key,
+                condition,
+                comparison,
+                changed,
+                members,
+            } => {
+                let store = &mut self.databases[connection.db];
+                let value = store.get_or_set_with(key, || StoreData::SortedSet(HashMap::new()));
+                let StoreData::SortedSet(sorted_set) = &mut value.data else {
+                    return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE))));
+                };
+                let mut added = 0;
+                let mut updated = 0;
+                for (score, member) in members {
+                    match sorted_set.get_mut(member) {
+                        Some(existing) => {
+                            let update = *condition != Some(SetCondition::NotExists)
+                                && match comparison {
+                                    Some(ScoreComparison::Greater) => score > existing,
+                                    Some(ScoreComparison::Less) => score < existing,
+                                    None => true,
+                                };
+                            if update && score != existing {
+                                *existing = *score;
+                                updated += 1;
                             }
                         }
-                        None => Ok(Some(Message::GetResponse(GetResponse::Found(
-                            value.data.clone(),
-                        )))),
+                        None if *condition != Some(SetCondition::Exists) => {
+                            sorted_set.insert(member.clone(), *score);
+                            added += 1;
+                        }
+                        None => {}
                     }
                 }
-                None => Ok(Some(Message::GetResponse(GetResponse::NotFound))),
-            },
+                // An `XX` that added nothing to a new key mustn't leave an empty set
+                if sorted_set.is_empty() {
+                    store.remove(key);
+                }
+                if added + updated > 0 {
+                    self.propagate(connection.db, message.clone());
+                }
+                let count = if *changed { added + updated } else { added };
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(count))))
+                }
+            }
+            Message::ZRem { key, members } => {
+                let store = &mut self.databases[connection.db];
+                let sorted_set = match store.get_mut(key) {
+                    Some(StoreValue {
+                        data: StoreData::SortedSet(sorted_set),
+                        ..
+                    }) => sorted_set,
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => return Ok(Some(Message::Reply(RespValue::Integer(0)))),
+                };
+                let removed = members
+                    .iter()
+                    .filter(|m| sorted_set.remove(*m).is_some())
+                    .count();
+                if sorted_set.is_empty() {
+                    store.remove(key);
+                }
+                if removed > 0 {
+                    self.propagate(connection.db, message.clone());
+                }
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::Integer(removed as i64))))
+                }
+            }
+            Message::ZCard { key } => {
+                let len = match self.databases[connection.db].get(key).map(|v| &v.data) {
+                    Some(StoreData::SortedSet(sorted_set)) => sorted_set.len(),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => 0,
+                };
+                Ok(Some(Message::Reply(RespValue::Integer(len as i64))))
+            }
+            Message::ZIncrBy {
+                key,
+                amount,
+                member,
+            } => {
+                let store = &mut self.databases[connection.db];
+                let score = match store.get(key).map(|v| &v.data) {
+                    Some(StoreData::SortedSet(sorted_set)) => sorted_set.get(member).copied(),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => None,
+                }
+                .unwrap_or(0.0)
+                    + amount;
+                // Adding opposite infinities
+                if score.is_nan() {
+                    return Ok(Some(Message::Reply(RespValue::SimpleError(
+                        "ERR resulting score is not a number (NaN)",
+                    ))));
+                }
+                let value = store.get_or_set_with(key, || StoreData::SortedSet(HashMap::new()));
+                if let StoreData::SortedSet(sorted_set) = &mut value.data {
+                    sorted_set.insert(member.clone(), score);
+                }
+                self.propagate(connection.db, message.clone());
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else {
+                    Ok(Some(Message::Reply(RespValue::OwnedBulkString(
+                        score.to_string(),
+                    ))))
+                }
+            }
+            Message::ZRange {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => {
+                let members = match self.databases[connection.db].get(key).map(|v| &v.data) {
+                    Some(StoreData::SortedSet(sorted_set)) => sorted_by_score(sorted_set),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => Vec::new(),
+                };
+                // Negative indices count back from the end, and the range is clamped
+                // to the set
+                let len = members.len() as i64;
+                let start = if *start < 0 { len + start } else { *start }.max(0);
+                let stop = if *stop < 0 { len + stop } else { *stop }.min(len - 1);
+                let range = if start <= stop {
+                    &members[start as usize..=stop as usize]
+                } else {
+                    &[]
+                };
+                let mut elements = Vec::new();
+                for (member, score) in range {
+                    elements.push(member.to_string());
+                    if *with_scores {
+                        elements.push(score.to_string());
+                    }
+                }
+                Ok(Some(Message::Reply(RespValue::bulk_strings(elements))))
+            }
+            Message::ZRank { key, member } | Message::ZRevRank { key, member } => {
+                let members = match self.databases[connection.db].get(key).map(|v| &v.data) {
+                    Some(StoreData::SortedSet(sorted_set)) => sorted_by_score(sorted_set),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => Vec::new(),
+                };
+                let rank =
+                    members
+                        .iter()
+                        .position(|(m, _)| *m == member)
+                        .map(|rank| match message {
+                            Message::ZRevRank { .. } => members.len() - 1 - rank,
+                            _ => rank,
+                        });
+                Ok(Some(Message::Reply(match rank {
+                    Some(rank) => RespValue::Integer(rank as i64),
+                    None => RespValue::NullBulkString,
+                })))
+            }
+            Message::SInter { keys } | Message::SUnion { keys } | Message::SDiff { keys } => {
+                let empty = HashSet::new();
+                let mut sets = Vec::with_capacity(keys.len());
+                for key in keys {
+                    sets.push(
+                        match self.databases[connection.db].get(key).map(|v| &v.data) {
+                            Some(StoreData::Set(set)) => set,
+                            Some(_) => {
+                                return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE))))
+                            }
+                            None => &empty,
+                        },
+                    );
+                }
+                let (first, rest) = sets.split_first().unwrap();
+                let members: HashSet<&String> = match message {
+                    Message::SInter { .. } => first
+                        .iter()
+                        .filter(|m| rest.iter().all(|s| s.contains(*m)))
+                        .collect(),
+                    Message::SUnion { .. } => sets.iter().flat_map(|s| s.iter()).collect(),
+                    _ => first
+                        .iter()
+                        .filter(|m| !rest.iter().any(|s| s.contains(*m)))
+                        .collect(),
+                };
+                Ok(Some(Message::Reply(RespValue::bulk_strings(members))))
+            }
+            Message::SPop { key, count } => {
+                let store = &mut self.databases[connection.db];
+                let set = match store.get_mut(key) {
+                    Some(StoreValue {
+                        data: StoreData::Set(set),
+                        ..
+                    }) => set,
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None if count.is_some() => {
+                        return Ok(Some(Message::Reply(RespValue::Array(Vec::new()))))
+                    }
+                    None => return Ok(Some(Message::GetResponse(GetResponse::NotFound))),
+                };
+                let mut popped = choose_distinct(set.iter().cloned().collect(), count.unwrap_or(1));
+                for member in &popped {
+                    set.remove(member);
+                }
+                // Empty sets don't exist
+                if set.is_empty() {
+                    store.remove(key);
+                }
+                if !popped.is_empty() {
+                    self.propagate(
+                        connection.db,
+                        Message::SRem {
+                            key: key.clone(),
+                            members: popped.clone(),
+                        },
+                    );
+                }
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else if count.is_some() {
+                    Ok(Some(Message::Reply(RespValue::bulk_strings(popped))))
+                } else {
+                    Ok(Some(Message::GetResponse(match popped.pop() {
+                        Some(member) => GetResponse::Found(member),
+                        None => GetResponse::NotFound,
+                    })))
+                }
+            }
+            Message::SRandMember { key, count } => {
+                let members = match self.databases[connection.db].get(key).map(|v| &v.data) {
+                    Some(StoreData::Set(set)) => set.iter().collect::<Vec<_>>(),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => Vec::new(),
+                };
+                let Some(count) = count else {
+                    return Ok(Some(Message::GetResponse(if members.is_empty() {
+                        GetResponse::NotFound
+                    } else {
+                        GetResponse::Found(members[random_index(members.len())].clone())
+                    })));
+                };
+                let chosen = if *count >= 0 {
+                    choose_distinct(members, *count as usize)
+                } else if members.is_empty() {
+                    Vec::new()
+                } else {
+                    // Members can repeat, so the reply can be far bigger than the set
+                    let len = usize::try_from(count.unsigned_abs()).unwrap_or(usize::MAX);
+                    let mut chosen = Vec::new();
+                    if chosen.try_reserve_exact(len).is_err() {
+                        return Ok(Some(Message::Reply(RespValue::SimpleError(
+                            "ERR value is out of range",
+                        ))));
+                    }
+                    chosen.extend((0..len).map(|_| members[random_index(members.len())]));
+                    chosen
+                };
+                Ok(Some(Message::Reply(RespValue::bulk_strings(chosen))))
+            }
+            Message::SIsMember { key, .. } | Message::SMIsMember { key, .. } => {
+                let set = match self.databases[connection.db].get(key).map(|v| &v.data) {
+                    Some(StoreData::Set(set)) => Some(set),
+                    Some(_) => return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE)))),
+                    None => None,
+                };
+                let is_member = |member: &String| {
+                    RespValue::Integer(set.is_some_and(|s| s.contains(member)) as i64)
+                };
+                Ok(Some(Message::Reply(match message {
+                    Message::SMIsMember { members, .. } => {
+                        RespValue::Array(members.iter().map(is_member).collect())
+                    }
+                    Message::SIsMember { member, .. } => is_member(member),
+                    _ => unreachable!(),
+                })))
+            }
+            Message::Set {
+                key,
+                value: value_string,
+                expiry,
+                keep_ttl,
+                condition,
+                return_old,
+            } => {
+                let max_len = self
+                    .config_value(ConfigKey::ProtoMaxBulkLen)
+                    .unwrap_or(DEFAULT_PROTO_MAX_BULK_LEN);
+                if value_string.len() > max_len {
+                    return Ok(Some(Message::Reply(RespValue::SimpleError(
+                        "ERR string exceeds maximum allowed size (proto-max-bulk-len)",
+                    ))));
+                }
+                let existing = self.databases[connection.db].get(key);
+                let old_value = match existing.map(|v| &v.data) {
+                    Some(StoreData::String(data)) => Some(data.clone()),
+                    // Overwriting another type is fine, unless the old value is asked for
+                    Some(_) if *return_old => {
+                        return Ok(Some(Message::Reply(RespValue::SimpleError(WRONGTYPE))))
+                    }
+                    _ => None,
+                };
+                let should_set = match condition {
+                    Some(SetCondition::NotExists) => existing.is_none(),
+                    Some(SetCondition::Exists) => existing.is_some(),
+                    None => true,
+                };
+                if should_set {
+                    let expiry = if *keep_ttl {
+                        existing.and_then(|v| v.expiry_from(Instant::now()))
+                    } else {
+                        *expiry
+                    };
+                    self.databases[connection.db].set_with_expiry(
+                        key.clone(),
+                        StoreData::String(value_string.clone()),
+                        expiry,
+                    );
+                    self.propagate(
+                        connection.db,
+                        Message::Set {
+                            key: key.clone(),
+                            value: value_string.clone(),
+                            expiry: expiry.map(StoreExpiry::absolute),
+                            keep_ttl: false,
+                            condition: None,
+                            return_old: false,
+                        },
+                    );
+                }
+                if matches!(connection.ty, ConnectionType::Master) {
+                    Ok(None)
+                } else if *return_old {
+                    Ok(Some(Message::GetResponse(match old_value {
+                        Some(value) => GetResponse::Found(value),
+                        None => GetResponse::NotFound,
+                    })))
+                } else if should_set {
+                    Ok(Some(Message::Ok))
+                } else {
+                    Ok(Some(Message::GetResponse(GetResponse::NotFound)))
+                }
+            }
             _ => match &mut self.role_state {
                 RoleState::Slave(slave_state) => match message {
                     Message::Ping => Ok(None),
-                    Message::Set { key, value, expiry } => {
-                        let value = StoreValue {
-                            data: value.to_string(),
-                            updated: Instant::now(),
-                            expiry: expiry.map(StoreExpiry::Duration),
-                        };
-                        self.store.data.insert(key.to_string(), value);
-                        if matches!(connection.ty, ConnectionType::Master) {
-                            Ok(None)
-                        } else {
-                            Ok(Some(Message::Ok))
-                        }
-                    }
                     Message::DatabaseFile(_) => Ok(None),
                     Message::Pong => {
                         if matches!(slave_state.handshake_state, HandshakeState::PingSent) {
@@ -247,25 +1517,16 @@ impl State {
                         }
                         Ok(None)
                     }
-                    Message::FullResync { .. } => {
+                    Message::FullResync { offset, .. } => {
                         if matches!(slave_state.handshake_state, HandshakeState::PSyncSent) {
                             slave_state.handshake_state = HandshakeState::Complete;
+                            // We're now in sync with the master as of its offset
+                            slave_state.offset = usize::try_from(*offset).unwrap_or(0);
                         }
                         Ok(None)
                     }
-                    Message::InfoRequest { sections } => {
-                        let mut section_maps = HashMap::new();
-                        if sections.is_empty() || sections.contains(&"replication".to_string()) {
-                            let mut section_map = HashMap::new();
-                            section_map.insert("role".to_string(), "slave".to_string());
-                            section_maps.insert("Replication".to_string(), section_map);
-                        }
-                        Ok(Some(Message::InfoResponse {
-                            sections: section_maps,
-                        }))
-                    }
                     Message::ReplicationConfig { key, value }
-                        if key.to_ascii_uppercase() == "GETACK" && value == "*" =>
+                        if key.eq_ignore_ascii_case("GETACK") && value == "*" =>
                     {
                         Ok(Some(Message::ReplicationConfig {
                             key: "ACK".into(),
@@ -282,34 +1543,21 @@ impl State {
                         Message::Ping => Ok(Some(Message::Pong)),
                         Message::Ok => Ok(None),
                         Message::Pong => Ok(None),
-                        Message::Set { key, value, expiry } => {
-                            let value = StoreValue {
-                                data: value.to_string(),
-                                updated: Instant::now(),
-                                expiry: expiry.map(StoreExpiry::Duration),
-                            };
-                            self.store.data.insert(key.to_string(), value);
-                            Ok(Some(Message::Ok))
-                        }
-                        Message::InfoRequest { sections } => {
-                            let mut section_maps = HashMap::new();
-                            if sections.is_empty() || sections.contains(&"replication".to_string())
-                            {
-                                let mut section_map = HashMap::new();
-                                section_map.insert("role".to_string(), "master".to_string());
-                                section_map.insert(
-                                    "master_replid".to_string(),
-                                    master_state.replication_id.clone(),
-                                );
-                                section_map.insert(
-                                    "master_repl_offset".to_string(),
-                                    master_state.replication_offset.to_string(),
-                                );
-                                section_maps.insert("Replication".to_string(), section_map);
+                        Message::ReplicationConfig { key, value }
+                            if key.eq_ignore_ascii_case("ACK") =>
+                        {
+                            // ACKs get no reply, so there's nobody to tell about a bad
+                            // offset, and the last good one stands
+                            match (connection.replica_id, value.parse::<usize>()) {
+                                (Some(replica_id), Ok(offset)) => {
+                                    master_state.replica_offsets.insert(replica_id, offset);
+                                }
+                                (Some(_), Err(_)) => {
+                                    eprintln!("ignoring malformed ACK offset {:?}", value)
+                                }
+                                (None, _) => {}
                             }
-                            Ok(Some(Message::InfoResponse {
-                                sections: section_maps,
-                            }))
+                            Ok(None)
                         }
                         Message::ReplicationConfig { .. } => {
                             // We know we're connected to a slave, rather than a client, now
@@ -330,9 +1578,37 @@ impl State {
                                 Ok(None)
                             }
                         }
-                        Message::Wait { .. } => Ok(Some(Message::WaitReply {
-                            num_replicas: master_state.num_replicas,
-                        })),
+                        Message::Wait {
+                            num_replicas,
+                            timeout,
+                        } => {
+                            let offset = master_state.write_offset;
+                            let acked = master_state.acked_replicas(offset);
+                            if acked >= *num_replicas {
+                                return Ok(Some(Message::WaitReply {
+                                    num_replicas: acked,
+                                }));
+                            }
+
+                            // Ask the replicas for their offsets, unless we already have
+                            // since these writes, and reply from `next_outgoing` once
+                            // enough have caught up or we time out
+                            if master_state.getack_offset != Some(offset) {
+                                let getack = Message::ReplicationConfig {
+                                    key: "GETACK".into(),
+                                    value: "*".into(),
+                                };
+                                master_state.replication_offset += serialized_len(&getack) as isize;
+                                master_state.getack_offset = Some(offset);
+                                self.propagated.push(getack);
+                            }
+                            connection.pending_wait = Some(PendingWait {
+                                offset,
+                                num_replicas: *num_replicas,
+                                deadline: (!timeout.is_zero()).then(|| Instant::now() + *timeout),
+                            });
+                            Ok(None)
+                        }
                         _ => Err(anyhow::format_err!(
                             "invalid message from client/replica {:?}",
                             message
@@ -343,6 +1619,159 @@ impl State {
         }
     }
 
+    /// Set an absolute expiry on an existing key, deleting it straight away if the
+    /// expiry isn't in the future.
+    fn expire_at(
+        &mut self,
+        key: &str,
+        unix_millis: i64,
+        connection: &Connection,
+    ) -> Option<Message> {
+        let updated = match self.databases[connection.db].get_mut(key) {
+            Some(value) => {
+                let effect = if unix_millis <= unix_time_millis() as i64 {
+                    self.databases[connection.db].remove(key);
+                    Message::Del {
+                        keys: vec![key.to_string()],
+                    }
+                } else {
+                    value.set_expiry(Some(StoreExpiry::UnixTimestampMillis(unix_millis as u64)));
+                    Message::PExpireAt {
+                        key: key.to_string(),
+                        unix_millis,
+                    }
+                };
+                self.propagate(connection.db, effect);
+                true
+            }
+            None => false,
+        };
+        if matches!(connection.ty, ConnectionType::Master) {
+            None
+        } else {
+            Some(Message::Reply(RespValue::Integer(updated as i64)))
+        }
+    }
+
+    /// The `INFO` reply for the requested sections, matched case-insensitively. No
+    /// sections, `all`, `default` or `everything` means every section, and unknown
+    /// sections are left out.
+    fn info(&self, sections: &[String]) -> Message {
+        let wanted = |name: &str| {
+            sections.is_empty()
+                || sections.iter().any(|s| {
+                    ["all", "default", "everything", name]
+                        .iter()
+                        .any(|n| s.eq_ignore_ascii_case(n))
+                })
+        };
+
+        let mut section_maps = HashMap::new();
+        if wanted("server") {
+            let mut section_map = HashMap::new();
+            section_map.insert("redis_version".to_string(), REDIS_VERSION.to_string());
+            section_map.insert("redis_mode".to_string(), "standalone".to_string());
+            section_map.insert("process_id".to_string(), std::process::id().to_string());
+            let port = match self.config.0.get(&ConfigKey::Port) {
+                Some(values) => values[0].clone(),
+                None => DEFAULT_PORT.to_string(),
+            };
+            section_map.insert("tcp_port".to_string(), port);
+            section_maps.insert("Server".to_string(), section_map);
+        }
+        if wanted("replication") {
+            let mut section_map = HashMap::new();
+            match &self.role_state {
+                RoleState::Slave(slave_state) => {
+                    section_map.insert("role".to_string(), "slave".to_string());
+                    section_map.insert(
+                        "slave_repl_offset".to_string(),
+                        slave_state.offset.to_string(),
+                    );
+                }
+                RoleState::Master(master_state) => {
+                    section_map.insert("role".to_string(), "master".to_string());
+                    section_map.insert(
+                        "master_replid".to_string(),
+                        master_state.replication_id.clone(),
+                    );
+                    section_map.insert(
+                        "master_repl_offset".to_string(),
+                        master_state.replication_offset.to_string(),
+                    );
+                }
+            }
+            section_maps.insert("Replication".to_string(), section_map);
+        }
+        if wanted("keyspace") {
+            let mut section_map = HashMap::new();
+            for (i, store) in self.databases.iter().enumerate() {
+                if let Some(info) = store.keyspace_info() {
+                    section_map.insert(format!("db{i}"), info);
+                }
+            }
+            section_maps.insert("Keyspace".to_string(), section_map);
+        }
+        Message::InfoResponse {
+            sections: section_maps,
+        }
+    }
+
+    /// Every key in `db` that hasn't expired, deleting the expired ones first. Commands
+    /// that walk the whole keyspace all go through here, so they always agree on
+    /// which keys exist.
+    fn iter_live_keys(&mut self, db: usize) -> impl Iterator<Item = (&String, &StoreValue)> {
+        self.expire_stale_keys(db);
+        self.databases[db].iter_live()
+    }
+
+    /// Delete every expired key in `db`.
+    fn expire_stale_keys(&mut self, db: usize) {
+        for key in self.databases[db].expired_keys() {
+            self.expire_if_stale(db, &key);
+        }
+    }
+
+    /// Delete `key` if it has expired, propagating a `DEL` so replicas drop it too.
+    /// Replicas never delete expired keys themselves, they wait for the master's `DEL`.
+    fn expire_if_stale(&mut self, db: usize, key: &str) {
+        if self.is_master() && self.databases[db].remove_expired(key) {
+            self.propagate(
+                db,
+                Message::Del {
+                    keys: vec![key.to_string()],
+                },
+            );
+        }
+    }
+
+    /// Record a write that changed the store, so it's propagated to replicas.
+    /// Only masters propagate, and only commands the registry flags as propagating.
+    /// Writes to a different database than the last one are preceded by a `SELECT`.
+    fn propagate(&mut self, db: usize, message: Message) {
+        if self.is_master() && Command::of(&message).is_some_and(|c| c.propagate) {
+            if self.propagated_db != Some(db) {
+                self.propagated_db = Some(db);
+                self.push_propagated(Message::Select { index: db as i64 });
+            }
+            self.push_propagated(message);
+        }
+    }
+
+    /// Queue a message for replicas, advancing the master's replication offset.
+    fn push_propagated(&mut self, message: Message) {
+        if let RoleState::Master(master_state) = &mut self.role_state {
+            master_state.replication_offset += serialized_len(&message) as isize;
+            master_state.write_offset = master_state.replication_offset as usize;
+        }
+        self.propagated.push(message);
+    }
+
+    /// Take the writes recorded since the last call, to be sent to replicas.
+    pub fn take_propagated(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.propagated)
+    }
+
     pub fn increment_offset(&mut self, bytes: usize) {
         match &mut self.role_state {
             RoleState::Slave(slave_state) => {
@@ -354,16 +1783,38 @@ impl State {
         }
     }
 
-    pub fn add_replica(&mut self) {
+    /// Stop tracking a replica that has disconnected, so `WAIT` no longer counts it.
+    pub fn remove_replica(&mut self, replica_id: usize) {
+        if let RoleState::Master(master_state) = &mut self.role_state {
+            master_state.replica_offsets.remove(&replica_id);
+        }
+    }
+
+    /// Register a new replica, returning the id used to track its acknowledged offset.
+    pub fn add_replica(&mut self) -> Option<usize> {
         match &mut self.role_state {
-            RoleState::Slave(_) => {}
+            RoleState::Slave(_) => None,
             RoleState::Master(master_state) => {
-                master_state.num_replicas += 1;
+                let replica_id = master_state.next_replica_id;
+                master_state.next_replica_id += 1;
+                master_state.replica_offsets.insert(replica_id, 0);
+                // The new replica starts out on database 0 while the others may not, so
+                // make sure the next write selects its database explicitly
+                self.propagated_db = None;
+                Some(replica_id)
             }
         }
     }
 }
 
+/// The number of bytes `message` takes up on the wire, which is what replication
+/// offsets count.
+fn serialized_len(message: &Message) -> usize {
+    let mut buf = BytesMut::new();
+    message.serialize(&mut buf);
+    buf.len()
+}
+
 impl std::fmt::Display for RoleState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -372,3 +1823,2849 @@ impl std::fmt::Display for RoleState {
         }
     }
 }
+
+/// The RDB file path, if both `dir` and `dbfilename` are configured.
+fn rdb_path(config: &Config) -> Option<PathBuf> {
+    let dir = config.0.get(&ConfigKey::Dir)?;
+    let filename = config.0.get(&ConfigKey::DbFilename)?;
+    let mut path = PathBuf::from(&dir[0]);
+    path.push(&filename[0]);
+    Some(path)
+}
+
+/// Quote a `MONITOR` argument, escaping anything that isn't printable ASCII.
+fn quote(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    for byte in arg.bytes() {
+        match byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x20..=0x7e => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RoleState, State};
+    use crate::{
+        command::COMMANDS,
+        config::{Config, ConfigKey},
+        message::Message,
+        resp_value::{Protocol, RespValue},
+        store::{unix_time_millis, StoreData, StoreExpiry},
+        Connection, ConnectionType,
+    };
+    use bytes::BytesMut;
+    use std::collections::HashMap;
+
+    fn client() -> Connection {
+        Connection {
+            ty: ConnectionType::Client,
+            send_rdb: false,
+            replica_id: None,
+            db: 0,
+            pending_wait: None,
+            addr: None,
+            monitor_id: None,
+            protocol: Protocol::Resp2,
+        }
+    }
+
+    /// Run a command against the state, returning the serialized response.
+    fn run(state: &mut State, connection: &mut Connection, args: &[&str]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        RespValue::Array(args.iter().map(|a| RespValue::BulkString(a)).collect())
+            .serialize(&mut buf);
+        let (message, _) = Message::deserialize(&buf).unwrap();
+        let mut output = BytesMut::new();
+        if let Some(response) = state.handle_incoming(&message, &buf, connection).unwrap() {
+            response.serialize_for(connection.protocol, &mut output);
+        }
+        output.to_vec()
+    }
+
+    /// The elements of an array reply of bulk strings, sorted.
+    fn sorted_strings(output: &[u8]) -> Vec<String> {
+        let (RespValue::Array(elements), _) = RespValue::deserialize(output).unwrap() else {
+            panic!("expected array");
+        };
+        let mut strings = elements
+            .iter()
+            .map(|e| match e {
+                RespValue::BulkString(s) => s.to_string(),
+                _ => panic!("expected bulk string"),
+            })
+            .collect::<Vec<_>>();
+        strings.sort();
+        strings
+    }
+
+    #[test]
+    fn set_get_option() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["SET", "foo", "one", "GET"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SET", "foo", "two", "GET"]),
+            b"$3\r\none\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$3\r\ntwo\r\n"
+        );
+
+        // Blocked by NX, but still returns the old value
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["SET", "foo", "three", "NX", "GET"]
+            ),
+            b"$3\r\ntwo\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$3\r\ntwo\r\n"
+        );
+
+        // Blocked by XX on a missing key
+        assert_eq!(
+            run(&mut state, &mut connection, &["SET", "bar", "one", "XX"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "bar"]),
+            b"$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn replica_ack_offset() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = Connection {
+            ty: ConnectionType::Slave,
+            send_rdb: false,
+            replica_id: state.add_replica(),
+            db: 0,
+            pending_wait: None,
+            addr: None,
+            monitor_id: None,
+            protocol: Protocol::Resp2,
+        };
+        let mut other_connection = Connection {
+            ty: ConnectionType::Slave,
+            send_rdb: false,
+            replica_id: state.add_replica(),
+            db: 0,
+            pending_wait: None,
+            addr: None,
+            monitor_id: None,
+            protocol: Protocol::Resp2,
+        };
+
+        assert!(run(&mut state, &mut connection, &["REPLCONF", "ACK", "42"]).is_empty());
+        assert!(run(&mut state, &mut other_connection, &["REPLCONF", "ACK", "7"]).is_empty());
+
+        let RoleState::Master(master_state) = &state.role_state else {
+            panic!("expected master");
+        };
+        assert_eq!(
+            master_state.replica_offsets[&connection.replica_id.unwrap()],
+            42
+        );
+        assert_eq!(
+            master_state.replica_offsets[&other_connection.replica_id.unwrap()],
+            7
+        );
+    }
+
+    #[test]
+    fn getex() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["GETEX", "foo"]),
+            b"$-1\r\n"
+        );
+
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "foo", "bar", "EX", "100"],
+        );
+        let expiry = state.databases[0].get("foo").unwrap().expiry;
+
+        // No options behaves like GET and leaves the TTL alone
+        assert_eq!(
+            run(&mut state, &mut connection, &["GETEX", "foo"]),
+            b"$3\r\nbar\r\n"
+        );
+        assert_eq!(state.databases[0].get("foo").unwrap().expiry, expiry);
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["GETEX", "foo", "PXAT", "4102444800000"]
+            ),
+            b"$3\r\nbar\r\n"
+        );
+        assert_eq!(
+            state.databases[0].get("foo").unwrap().expiry,
+            Some(StoreExpiry::UnixTimestampMillis(4102444800000))
+        );
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["GETEX", "foo", "PERSIST"]),
+            b"$3\r\nbar\r\n"
+        );
+        assert_eq!(state.databases[0].get("foo").unwrap().expiry, None);
+
+        run(&mut state, &mut connection, &["GETEX", "foo", "EX", "10"]);
+        assert!(matches!(
+            state.databases[0].get("foo").unwrap().expiry,
+            Some(StoreExpiry::UnixTimestampMillis(_))
+        ));
+    }
+
+    #[test]
+    fn propagate_only_changes() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar", "NX"]);
+        let propagated = state.take_propagated();
+        assert_eq!(propagated.len(), 1);
+        assert!(matches!(
+            &propagated[0],
+            Message::Set { key, value, condition: None, .. } if key == "foo" && value == "bar"
+        ));
+
+        // Key exists, so the NX write is a no-op and isn't propagated
+        run(&mut state, &mut connection, &["SET", "foo", "baz", "NX"]);
+        assert!(state.take_propagated().is_empty());
+
+        // Reads are never propagated
+        run(&mut state, &mut connection, &["GET", "foo"]);
+        assert!(state.take_propagated().is_empty());
+    }
+
+    #[test]
+    fn replay_propagated_writes() {
+        let mut master = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(
+            &mut master,
+            &mut connection,
+            &["SET", "counter", "1", "EX", "100"],
+        );
+        assert_eq!(
+            run(&mut master, &mut connection, &["INCR", "counter"]),
+            b":2\r\n"
+        );
+        assert_eq!(
+            run(&mut master, &mut connection, &["INCR", "counter"]),
+            b":3\r\n"
+        );
+        run(
+            &mut master,
+            &mut connection,
+            &["SET", "foo", "bar", "PX", "100000"],
+        );
+        run(&mut master, &mut connection, &["SET", "foo", "baz", "NX"]);
+        run(&mut master, &mut connection, &["INCR", "other"]);
+
+        let propagated = master.take_propagated();
+        assert_eq!(propagated.len(), 5);
+
+        // Each INCR propagates its own distinct resulting value
+        assert!(matches!(
+            &propagated[1],
+            Message::Set { key, value, .. } if key == "counter" && value == "2"
+        ));
+        assert!(matches!(
+            &propagated[2],
+            Message::Set { key, value, .. } if key == "counter" && value == "3"
+        ));
+
+        let mut config = Config::default();
+        config.0.insert(
+            ConfigKey::ReplicaOf,
+            vec!["localhost".to_string(), "6379".to_string()],
+        );
+        let mut replica = State::new(config).unwrap();
+        let mut master_connection = Connection {
+            ty: ConnectionType::Master,
+            send_rdb: false,
+            replica_id: None,
+            db: 0,
+            pending_wait: None,
+            addr: None,
+            monitor_id: None,
+            protocol: Protocol::Resp2,
+        };
+        for message in propagated {
+            let mut buf = BytesMut::new();
+            message.serialize(&mut buf);
+            let (message, _) = Message::deserialize(&buf).unwrap();
+            assert!(replica
+                .handle_incoming(&message, &buf, &mut master_connection)
+                .unwrap()
+                .is_none());
+        }
+
+        assert_eq!(
+            master.databases[0].iter_live().count(),
+            replica.databases[0].iter_live().count()
+        );
+        for (key, value) in master.databases[0].iter_live() {
+            let replica_value = replica.databases[0].get(key).unwrap();
+            assert_eq!(value.data, replica_value.data);
+            assert_eq!(value.expiry.is_some(), replica_value.expiry.is_some());
+        }
+    }
+
+    #[test]
+    fn exists() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "gone", "bar", "PX", "1"],
+        );
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXISTS", "foo"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXISTS", "missing"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXISTS", "gone"]),
+            b":0\r\n"
+        );
+
+        // Repeated keys are counted each time
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["EXISTS", "foo", "foo", "missing", "foo"]
+            ),
+            b":3\r\n"
+        );
+    }
+
+    #[test]
+    fn type_command() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["TYPE", "foo"]),
+            b"+string\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TYPE", "missing"]),
+            b"+none\r\n"
+        );
+    }
+
+    #[test]
+    fn expire() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXPIRE", "missing", "10"]),
+            b":0\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXPIRE", "foo", "100"]),
+            b":1\r\n"
+        );
+        assert!(state.databases[0].get("foo").unwrap().expiry.is_some());
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["PEXPIRE", "foo", "1"]),
+            b":1\r\n"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$-1\r\n"
+        );
+
+        // Non-positive TTLs delete the key immediately
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXPIRE", "foo", "0"]),
+            b":1\r\n"
+        );
+        assert!(state.databases[0].get("foo").is_none());
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["PEXPIRE", "foo", "-5"]),
+            b":1\r\n"
+        );
+        assert!(state.databases[0].get("foo").is_none());
+    }
+
+    #[test]
+    fn expireat() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["EXPIREAT", "missing", "4102444800"]
+            ),
+            b":0\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["PEXPIREAT", "foo", "4102444800000"]
+            ),
+            b":1\r\n"
+        );
+        assert_eq!(
+            state.databases[0].get("foo").unwrap().expiry,
+            Some(StoreExpiry::UnixTimestampMillis(4102444800000))
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$3\r\nbar\r\n"
+        );
+
+        // A timestamp in the past deletes the key
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXPIREAT", "foo", "1000"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn ttl() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "missing"]),
+            b":-2\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["PTTL", "missing"]),
+            b":-2\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "foo"]),
+            b":-1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["PTTL", "foo"]),
+            b":-1\r\n"
+        );
+
+        // Relative expiry
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "foo", "bar", "EX", "100"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "foo"]),
+            b":100\r\n"
+        );
+        let pttl = run(&mut state, &mut connection, &["PTTL", "foo"]);
+        let pttl = std::str::from_utf8(&pttl[1..pttl.len() - 2])
+            .unwrap()
+            .parse::<i64>()
+            .unwrap();
+        assert!(pttl > 99_000 && pttl <= 100_000);
+
+        // Absolute expiry
+        let at = (unix_time_millis() + 50_000).to_string();
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "foo", "bar", "PXAT", &at],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "foo"]),
+            b":50\r\n"
+        );
+    }
+
+    #[test]
+    fn persist() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["PERSIST", "missing"]),
+            b":0\r\n"
+        );
+
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "foo", "bar", "PX", "100000"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["PERSIST", "foo"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["PTTL", "foo"]),
+            b":-1\r\n"
+        );
+
+        // No expiry left to remove
+        assert_eq!(
+            run(&mut state, &mut connection, &["PERSIST", "foo"]),
+            b":0\r\n"
+        );
+    }
+
+    #[test]
+    fn command_count() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["COMMAND", "COUNT"]),
+            format!(":{}\r\n", COMMANDS.len()).as_bytes()
+        );
+    }
+
+    #[test]
+    fn command_docs() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        // Under RESP2, the map comes back as a flat array of names and details
+        let output = run(&mut state, &mut connection, &["COMMAND", "DOCS"]);
+        assert!(output.starts_with(format!("*{}\r\n", COMMANDS.len() * 2).as_bytes()));
+        let (RespValue::Array(elements), _) = RespValue::deserialize(&output).unwrap() else {
+            panic!("expected array");
+        };
+        assert_eq!(elements[0], RespValue::BulkString("ping"));
+        assert_eq!(elements[1], RespValue::Array(vec![]));
+
+        connection.protocol = Protocol::Resp3;
+        let output = run(&mut state, &mut connection, &["COMMAND", "DOCS"]);
+        assert!(output.starts_with(format!("%{}\r\n", COMMANDS.len()).as_bytes()));
+        let (RespValue::Map(entries), _) = RespValue::deserialize(&output).unwrap() else {
+            panic!("expected map");
+        };
+        assert!(entries.contains(&(RespValue::BulkString("get"), RespValue::Map(vec![]))));
+    }
+
+    #[test]
+    fn getex_propagation() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        state.take_propagated();
+
+        // Plain GETEX is a read
+        run(&mut state, &mut connection, &["GETEX", "foo"]);
+        assert!(state.take_propagated().is_empty());
+
+        // Relative expiry is propagated as an absolute one
+        let before = unix_time_millis() as i64;
+        run(&mut state, &mut connection, &["GETEX", "foo", "EX", "100"]);
+        let propagated = state.take_propagated();
+        assert_eq!(propagated.len(), 1);
+        assert!(matches!(
+            &propagated[0],
+            Message::PExpireAt { key, unix_millis }
+                if key == "foo"
+                    && *unix_millis >= before + 100_000
+                    && *unix_millis <= unix_time_millis() as i64 + 100_000
+        ));
+
+        run(&mut state, &mut connection, &["GETEX", "foo", "PERSIST"]);
+        let propagated = state.take_propagated();
+        assert_eq!(propagated.len(), 1);
+        assert!(matches!(&propagated[0], Message::Persist { key } if key == "foo"));
+
+        // Nothing left to persist
+        run(&mut state, &mut connection, &["GETEX", "foo", "PERSIST"]);
+        assert!(state.take_propagated().is_empty());
+    }
+
+    #[test]
+    fn keys_pattern() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "hello", "1"]);
+        run(&mut state, &mut connection, &["SET", "hallo", "2"]);
+        run(&mut state, &mut connection, &["SET", "other", "3"]);
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "hxllo", "4", "PX", "1"],
+        );
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        let keys = |state: &mut State, connection: &mut Connection, pattern: &str| {
+            let mut buf = BytesMut::new();
+            RespValue::Array(vec![
+                RespValue::BulkString("KEYS"),
+                RespValue::BulkString(pattern),
+            ])
+            .serialize(&mut buf);
+            let (message, _) = Message::deserialize(&buf).unwrap();
+            match state.handle_incoming(&message, &buf, connection).unwrap() {
+                Some(Message::KeysResponse { mut keys }) => {
+                    keys.sort();
+                    keys
+                }
+                response => panic!("unexpected response {:?}", response),
+            }
+        };
+
+        assert_eq!(
+            keys(&mut state, &mut connection, "h?llo"),
+            vec!["hallo", "hello"]
+        );
+        assert_eq!(keys(&mut state, &mut connection, "h[e]llo"), vec!["hello"]);
+        assert_eq!(keys(&mut state, &mut connection, "other"), vec!["other"]);
+        assert_eq!(keys(&mut state, &mut connection, "*").len(), 3);
+    }
+
+    #[test]
+    fn rename() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["RENAME", "missing", "other"]),
+            b"-ERR no such key\r\n"
+        );
+
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "foo", "bar", "EX", "100"],
+        );
+        run(&mut state, &mut connection, &["SET", "other", "old"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["RENAME", "foo", "other"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "other"]),
+            b"$3\r\nbar\r\n"
+        );
+        // The TTL moves with the value
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "other"]),
+            b":100\r\n"
+        );
+    }
+
+    #[test]
+    fn renamenx() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["RENAMENX", "missing", "other"]
+            ),
+            b"-ERR no such key\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        run(&mut state, &mut connection, &["SET", "other", "old"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["RENAMENX", "foo", "other"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "other"]),
+            b"$3\r\nold\r\n"
+        );
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["RENAMENX", "foo", "new"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "new"]),
+            b"$3\r\nbar\r\n"
+        );
+    }
+
+    #[test]
+    fn copy() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["COPY", "missing", "other"]),
+            b":0\r\n"
+        );
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "foo", "bar", "EX", "100"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["COPY", "foo", "foo"]),
+            b"-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["COPY", "foo", "other"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "other"]),
+            b"$3\r\nbar\r\n"
+        );
+        // The source TTL is copied too
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "other"]),
+            b":100\r\n"
+        );
+
+        // An existing destination is only overwritten with REPLACE
+        run(&mut state, &mut connection, &["SET", "foo", "baz"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["COPY", "foo", "other"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["COPY", "foo", "other", "REPLACE"]
+            ),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "other"]),
+            b":-1\r\n"
+        );
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["COPY", "foo", "foo", "DB", "1"]
+            ),
+            b":1\r\n"
+        );
+        run(&mut state, &mut connection, &["SELECT", "1"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$3\r\nbaz\r\n"
+        );
+    }
+
+    #[test]
+    fn dump_restore() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["DUMP", "missing"]),
+            b"$-1\r\n"
+        );
+        run(
+            &mut state,
+            &mut connection,
+            &["HSET", "hash", "field", "value"],
+        );
+        let output = run(&mut state, &mut connection, &["DUMP", "hash"]);
+        let (RespValue::BulkString(payload), _) = RespValue::deserialize(&output).unwrap() else {
+            panic!("expected bulk string");
+        };
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["RESTORE", "hash", "0", payload]
+            ),
+            b"-BUSYKEY Target key name already exists.\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["RESTORE", "copy", "0", "00"]),
+            b"-ERR DUMP payload version or checksum are wrong\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["RESTORE", "copy", "0", "00c30b000000000000000000"]
+            ),
+            b"-ERR DUMP payload version or checksum are wrong\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["RESTORE", "copy", "0", payload]
+            ),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["HGETALL", "copy"]),
+            b"*2\r\n$5\r\nfield\r\n$5\r\nvalue\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "copy"]),
+            b":-1\r\n"
+        );
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["RESTORE", "copy", "100000", payload, "REPLACE"]
+            ),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "copy"]),
+            b":100\r\n"
+        );
+
+        // With ABSTTL the TTL is a unix time in milliseconds
+        let expires_at = (unix_time_millis() + 200_000).to_string();
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["RESTORE", "copy", &expires_at, payload, "REPLACE", "ABSTTL"]
+            ),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "copy"]),
+            b":200\r\n"
+        );
+        let expires_at = (unix_time_millis() - 1000).to_string();
+        run(
+            &mut state,
+            &mut connection,
+            &["RESTORE", "copy", &expires_at, payload, "REPLACE", "ABSTTL"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXISTS", "copy"]),
+            b":0\r\n"
+        );
+    }
+
+    #[test]
+    fn config_set_not_propagated() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["CONFIG", "SET", "dir", "/tmp"]
+            ),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["CONFIG", "GET", "dir"]),
+            b"*2\r\n$3\r\ndir\r\n$4\r\n/tmp\r\n"
+        );
+        assert!(state.take_propagated().is_empty());
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        let propagated = state.take_propagated();
+        assert_eq!(propagated.len(), 1);
+        assert!(matches!(&propagated[0], Message::Set { key, .. } if key == "foo"));
+    }
+
+    #[test]
+    fn append() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        // A missing key is created as a plain string
+        assert_eq!(
+            run(&mut state, &mut connection, &["APPEND", "foo", "Hello"]),
+            b":5\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TYPE", "foo"]),
+            b"+string\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["APPEND", "foo", " World"]),
+            b":11\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$11\r\nHello World\r\n"
+        );
+
+        // Appending keeps any TTL
+        run(&mut state, &mut connection, &["EXPIRE", "foo", "100"]);
+        run(&mut state, &mut connection, &["APPEND", "foo", "!"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["TTL", "foo"]),
+            b":100\r\n"
+        );
+
+        // Both the create and the append propagate as APPEND
+        let propagated = state.take_propagated();
+        assert_eq!(
+            propagated
+                .iter()
+                .filter(|m| matches!(m, Message::Append { .. }))
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn info_keyspace() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["INFO", "keyspace"]),
+            b"$9\r\n#Keyspace\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "1"]);
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "bar", "2", "EX", "100"],
+        );
+        let info = "#Keyspace\ndb0:keys=2,expires=1,avg_ttl=0";
+        assert_eq!(
+            run(&mut state, &mut connection, &["INFO", "keyspace"]),
+            format!("${}\r\n{info}\r\n", info.len()).as_bytes()
+        );
+
+        run(&mut state, &mut connection, &["SELECT", "1"]);
+        run(&mut state, &mut connection, &["SET", "baz", "3"]);
+        let info = run(&mut state, &mut connection, &["INFO", "keyspace"]);
+        let info = String::from_utf8(info).unwrap();
+        assert!(info.contains("db0:keys=2,expires=1,avg_ttl=0"));
+        assert!(info.contains("db1:keys=1,expires=0,avg_ttl=0"));
+    }
+
+    #[test]
+    fn touch() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "1"]);
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "bar", "2", "PX", "100"],
+        );
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "gone", "3", "PX", "1"],
+        );
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        let updated = state.databases[0].get("foo").unwrap().updated;
+        let accessed = state.databases[0].get("foo").unwrap().accessed;
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["TOUCH", "foo", "bar", "gone", "missing"]
+            ),
+            b":2\r\n"
+        );
+        assert!(state.databases[0].get("foo").unwrap().accessed > accessed);
+        // Touching doesn't move the base that relative expiries count from
+        assert_eq!(state.databases[0].get("foo").unwrap().updated, updated);
+    }
+
+    #[test]
+    fn del() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "1"]);
+        run(&mut state, &mut connection, &["SET", "bar", "2"]);
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["DEL", "foo", "bar", "missing"]
+            ),
+            b":2\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXISTS", "foo", "bar"]),
+            b":0\r\n"
+        );
+        assert_eq!(run(&mut state, &mut connection, &["DEL", "foo"]), b":0\r\n");
+    }
+
+    #[test]
+    fn unlink() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "1"]);
+        run(&mut state, &mut connection, &["SET", "bar", "2"]);
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["UNLINK", "foo", "bar", "missing"]
+            ),
+            b":2\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXISTS", "foo", "bar"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["UNLINK", "foo"]),
+            b":0\r\n"
+        );
+    }
+
+    #[test]
+    fn select() {
+        let mut config = Config::default();
+        config.0.insert(ConfigKey::Databases, vec!["4".to_string()]);
+        let mut state = State::new(config).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["SELECT", "4"]),
+            b"-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SELECT", "-1"]),
+            b"-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SELECT", "3"]),
+            b"+OK\r\n"
+        );
+
+        // Databases are independent
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        run(&mut state, &mut connection, &["SELECT", "0"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn select_propagation() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "1"]);
+        run(&mut state, &mut connection, &["SELECT", "2"]);
+        run(&mut state, &mut connection, &["SET", "bar", "2"]);
+        run(&mut state, &mut connection, &["SET", "baz", "3"]);
+
+        // Only the change of database is propagated as a SELECT
+        let propagated = state.take_propagated();
+        assert_eq!(propagated.len(), 4);
+        assert!(matches!(&propagated[0], Message::Set { key, .. } if key == "foo"));
+        assert!(matches!(&propagated[1], Message::Select { index: 2 }));
+        assert!(matches!(&propagated[2], Message::Set { key, .. } if key == "bar"));
+        assert!(matches!(&propagated[3], Message::Set { key, .. } if key == "baz"));
+    }
+
+    #[test]
+    fn object_encoding() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["OBJECT", "ENCODING", "missing"]
+            ),
+            b"-ERR no such key\r\n"
+        );
+
+        let embstr = "a".repeat(44);
+        let raw = "a".repeat(45);
+        run(&mut state, &mut connection, &["SET", "int", "-12345"]);
+        run(&mut state, &mut connection, &["SET", "embstr", &embstr]);
+        run(&mut state, &mut connection, &["SET", "raw", &raw]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["OBJECT", "ENCODING", "int"]),
+            b"$3\r\nint\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["OBJECT", "ENCODING", "embstr"]
+            ),
+            b"$6\r\nembstr\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["OBJECT", "ENCODING", "raw"]),
+            b"$3\r\nraw\r\n"
+        );
+
+        // Integers only count if they'd be written back the same way
+        for s in ["+5", "007", "-0"] {
+            run(&mut state, &mut connection, &["SET", "padded", s]);
+            assert_eq!(
+                run(
+                    &mut state,
+                    &mut connection,
+                    &["OBJECT", "ENCODING", "padded"]
+                ),
+                b"$6\r\nembstr\r\n"
+            );
+        }
+        run(&mut state, &mut connection, &["SADD", "set", "1", "+2"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["OBJECT", "ENCODING", "set"]),
+            b"$8\r\nlistpack\r\n"
+        );
+    }
+
+    #[test]
+    fn malformed_ack() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+        let mut replica_connection = Connection {
+            ty: ConnectionType::Slave,
+            send_rdb: false,
+            replica_id: state.add_replica(),
+            db: 0,
+            pending_wait: None,
+            addr: None,
+            monitor_id: None,
+            protocol: Protocol::Resp2,
+        };
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        let RoleState::Master(master_state) = &state.role_state else {
+            panic!("expected master");
+        };
+        let acked = master_state.replication_offset.to_string();
+        run(
+            &mut state,
+            &mut replica_connection,
+            &["REPLCONF", "ACK", &acked],
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut replica_connection,
+                &["REPLCONF", "ACK", "abc"]
+            ),
+            b""
+        );
+
+        // The replica still counts as caught up with its last good offset
+        assert_eq!(
+            run(&mut state, &mut connection, &["WAIT", "1", "0"]),
+            b":1\r\n"
+        );
+    }
+
+    #[test]
+    fn wait_for_lazy_expiry() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+        let mut replica_connection = Connection {
+            ty: ConnectionType::Slave,
+            send_rdb: false,
+            replica_id: state.add_replica(),
+            db: 0,
+            pending_wait: None,
+            addr: None,
+            monitor_id: None,
+            protocol: Protocol::Resp2,
+        };
+        let offset = |state: &State| match &state.role_state {
+            RoleState::Master(master_state) => master_state.replication_offset,
+            RoleState::Slave(_) => panic!("expected master"),
+        };
+
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "foo", "bar", "PX", "1"],
+        );
+        let acked = offset(&state).to_string();
+        run(
+            &mut state,
+            &mut replica_connection,
+            &["REPLCONF", "ACK", &acked],
+        );
+        state.take_propagated();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        // Reading the expired key deletes it, and the propagated DEL advances the offset
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$-1\r\n"
+        );
+        assert!(state.databases[0].iter_live().next().is_none());
+        let propagated = state.take_propagated();
+        assert!(matches!(&propagated[..], [Message::Del { keys }] if keys == &["foo"]));
+        assert!(offset(&state) > acked.parse().unwrap());
+
+        // So WAIT has to wait for the replica to acknowledge the DEL
+        assert!(run(&mut state, &mut connection, &["WAIT", "1", "1000"]).is_empty());
+        assert!(matches!(
+            &state.take_propagated()[..],
+            [Message::ReplicationConfig { key, .. }] if key == "GETACK"
+        ));
+        assert!(state.next_outgoing(&mut connection).unwrap().is_none());
+
+        let acked = offset(&state).to_string();
+        run(
+            &mut state,
+            &mut replica_connection,
+            &["REPLCONF", "ACK", &acked],
+        );
+        assert!(matches!(
+            state.next_outgoing(&mut connection).unwrap(),
+            Some(Message::WaitReply { num_replicas: 1 })
+        ));
+    }
+
+    #[test]
+    fn wrong_type() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+        state.databases[0].set(
+            "list".to_string(),
+            StoreData::List(["a".to_string()].into()),
+        );
+
+        let wrong_type = b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "list"]),
+            wrong_type
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GETEX", "list"]),
+            wrong_type
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["INCR", "list"]),
+            wrong_type
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["APPEND", "list", "b"]),
+            wrong_type
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TYPE", "list"]),
+            b"+list\r\n"
+        );
+
+        // SET replaces a value of any type
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["SET", "list", "now a string"]
+            ),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TYPE", "list"]),
+            b"+string\r\n"
+        );
+    }
+
+    #[test]
+    fn client_pause() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+        let message = |args: &[&str]| {
+            let mut buf = BytesMut::new();
+            RespValue::Array(args.iter().map(|a| RespValue::BulkString(a)).collect())
+                .serialize(&mut buf);
+            Message::deserialize(&buf).unwrap().0
+        };
+        let set = message(&["SET", "foo", "bar"]);
+        let get = message(&["GET", "foo"]);
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["CLIENT", "PAUSE", "200", "WRITE"]
+            ),
+            b"+OK\r\n"
+        );
+        assert!(state.pause_remaining(&set, &connection).is_some());
+        assert!(state.pause_remaining(&get, &connection).is_none());
+
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(state.pause_remaining(&set, &connection).is_none());
+
+        run(&mut state, &mut connection, &["CLIENT", "PAUSE", "10000"]);
+        assert!(state.pause_remaining(&get, &connection).is_some());
+        assert_eq!(
+            run(&mut state, &mut connection, &["CLIENT", "UNPAUSE"]),
+            b"+OK\r\n"
+        );
+        assert!(state.pause_remaining(&get, &connection).is_none());
+    }
+
+    #[test]
+    fn push() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["RPUSH", "list", "c", "d"]),
+            b":2\r\n"
+        );
+        // Left pushes insert each value at the head in turn, reversing their order
+        assert_eq!(
+            run(&mut state, &mut connection, &["LPUSH", "list", "b", "a"]),
+            b":4\r\n"
+        );
+        assert_eq!(
+            state.databases[0].get("list").unwrap().data,
+            StoreData::List(["a", "b", "c", "d"].map(String::from).into())
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["TYPE", "list"]),
+            b"+list\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "string", "foo"]);
+        let wrong_type = b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        assert_eq!(
+            run(&mut state, &mut connection, &["LPUSH", "string", "a"]),
+            wrong_type
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["RPUSH", "string", "a"]),
+            wrong_type
+        );
+    }
+
+    #[test]
+    fn pop() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["LPOP", "list"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["LPOP", "list", "2"]),
+            b"*-1\r\n"
+        );
+
+        run(
+            &mut state,
+            &mut connection,
+            &["RPUSH", "list", "a", "b", "c", "d", "e"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["LPOP", "list"]),
+            b"$1\r\na\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["RPOP", "list"]),
+            b"$1\r\ne\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["RPOP", "list", "2"]),
+            b"*2\r\n$1\r\nd\r\n$1\r\nc\r\n"
+        );
+
+        // Popping the last element removes the key
+        assert_eq!(
+            run(&mut state, &mut connection, &["LPOP", "list", "5"]),
+            b"*1\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXISTS", "list"]),
+            b":0\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "string", "foo"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["LPOP", "string"]),
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+    }
+
+    #[test]
+    fn append_to_integer() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "n", "123"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["OBJECT", "ENCODING", "n"]),
+            b"$3\r\nint\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["APPEND", "n", "4"]),
+            b":4\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "n"]),
+            b"$4\r\n1234\r\n"
+        );
+    }
+
+    #[test]
+    fn lset() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["LSET", "list", "0", "x"]),
+            b"-ERR no such key\r\n"
+        );
+
+        run(
+            &mut state,
+            &mut connection,
+            &["RPUSH", "list", "a", "b", "c"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["LSET", "list", "0", "x"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["LSET", "list", "-1", "z"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            state.databases[0].get("list").unwrap().data,
+            StoreData::List(["x", "b", "z"].map(String::from).into())
+        );
+        for index in ["3", "-4"] {
+            assert_eq!(
+                run(&mut state, &mut connection, &["LSET", "list", index, "y"]),
+                b"-ERR index out of range\r\n"
+            );
+        }
+
+        run(&mut state, &mut connection, &["SET", "string", "foo"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["LSET", "string", "0", "x"]),
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+    }
+
+    #[test]
+    fn expire_zero_deletes() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        state.take_propagated();
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXPIRE", "foo", "0"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXISTS", "foo"]),
+            b":0\r\n"
+        );
+        let propagated = state.take_propagated();
+        assert!(matches!(&propagated[..], [Message::Del { keys }] if keys == &["foo"]));
+    }
+
+    #[test]
+    fn concurrent_waits_share_getack() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+        let mut other_connection = client();
+        let mut replica_connection = Connection {
+            ty: ConnectionType::Slave,
+            send_rdb: false,
+            replica_id: state.add_replica(),
+            db: 0,
+            pending_wait: None,
+            addr: None,
+            monitor_id: None,
+            protocol: Protocol::Resp2,
+        };
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        state.take_propagated();
+        assert!(run(&mut state, &mut connection, &["WAIT", "1", "1000"]).is_empty());
+        assert!(run(&mut state, &mut other_connection, &["WAIT", "1", "1000"]).is_empty());
+        let propagated = state.take_propagated();
+        assert_eq!(propagated.len(), 1);
+        assert!(matches!(
+            &propagated[0],
+            Message::ReplicationConfig { key, .. } if key == "GETACK"
+        ));
+
+        // The replica's reply to the one GETACK, which doesn't count the GETACK
+        // itself, satisfies both waiters
+        let RoleState::Master(master_state) = &state.role_state else {
+            panic!("expected master");
+        };
+        let acked = master_state.write_offset.to_string();
+        run(
+            &mut state,
+            &mut replica_connection,
+            &["REPLCONF", "ACK", &acked],
+        );
+        for connection in [&mut connection, &mut other_connection] {
+            assert!(matches!(
+                state.next_outgoing(connection).unwrap(),
+                Some(Message::WaitReply { num_replicas: 1 })
+            ));
+        }
+    }
+
+    #[test]
+    fn wait_without_writes() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["WAIT", "0", "100"]),
+            b":0\r\n"
+        );
+
+        // A new replica is trivially caught up when nothing has been written
+        state.add_replica();
+        assert_eq!(
+            run(&mut state, &mut connection, &["WAIT", "1", "100"]),
+            b":1\r\n"
+        );
+        assert!(connection.pending_wait.is_none());
+        assert!(state.take_propagated().is_empty());
+    }
+
+    #[test]
+    fn sadd_srem() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["SADD", "set", "a", "b", "a"]),
+            b":2\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SADD", "set", "b", "c"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            state.databases[0].get("set").unwrap().data,
+            StoreData::Set(["a", "b", "c"].map(String::from).into())
+        );
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["SREM", "set", "a", "x"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SREM", "set", "b", "c", "b"]),
+            b":2\r\n"
+        );
+        assert!(state.databases[0].get("set").is_none());
+        assert_eq!(
+            run(&mut state, &mut connection, &["SREM", "set", "a"]),
+            b":0\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        for command in ["SADD", "SREM"] {
+            assert_eq!(
+                run(&mut state, &mut connection, &[command, "foo", "a"]),
+                format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn scan_match_type() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+        let hash = || StoreData::Hash([("field".to_string(), "value".to_string())].into());
+        for key in ["user:1", "user:3", "other:1"] {
+            state.databases[0].set(key.to_string(), hash());
+        }
+        run(&mut state, &mut connection, &["SET", "user:4", "string"]);
+        run(&mut state, &mut connection, &["SADD", "user:5", "member"]);
+        state.databases[0].set_with_expiry(
+            "user:6".to_string(),
+            hash(),
+            Some(StoreExpiry::UnixTimestampMillis(1)),
+        );
+        state.take_propagated();
+
+        // Scan one key at a time and all at once, collecting every batch's matches
+        for count in ["1", "50"] {
+            let mut cursor = "0".to_string();
+            let mut keys = Vec::new();
+            loop {
+                let output = run(
+                    &mut state,
+                    &mut connection,
+                    &[
+                        "SCAN", &cursor, "MATCH", "user:*", "TYPE", "hash", "COUNT", count,
+                    ],
+                );
+                let (RespValue::Array(reply), _) = RespValue::deserialize(&output).unwrap() else {
+                    panic!("expected array");
+                };
+                let [RespValue::BulkString(next), RespValue::Array(batch)] = &reply[..] else {
+                    panic!("unexpected reply {:?}", reply);
+                };
+                keys.extend(batch.iter().map(|k| match k {
+                    RespValue::BulkString(k) => k.to_string(),
+                    _ => panic!("expected bulk string"),
+                }));
+                cursor = next.to_string();
+                if cursor == "0" {
+                    break;
+                }
+            }
+            keys.sort();
+            assert_eq!(keys, ["user:1", "user:3"]);
+        }
+
+        // The expired key was deleted along the way
+        let propagated = state.take_propagated();
+        assert!(matches!(&propagated[..], [Message::Del { keys }] if keys == &["user:6"]));
+    }
+
+    #[test]
+    fn keyspace_views_agree_on_expiry() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(run(&mut state, &mut connection, &["RANDOMKEY"]), b"$-1\r\n");
+        run(&mut state, &mut connection, &["SET", "live", "value"]);
+        run(
+            &mut state,
+            &mut connection,
+            &["SET", "expiring", "value", "PX", "1"],
+        );
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["KEYS", "*"]),
+            b"*1\r\n$4\r\nlive\r\n"
+        );
+        assert_eq!(run(&mut state, &mut connection, &["DBSIZE"]), b":1\r\n");
+        assert_eq!(
+            run(&mut state, &mut connection, &["SCAN", "0"]),
+            b"*2\r\n$1\r\n0\r\n*1\r\n$4\r\nlive\r\n"
+        );
+        for _ in 0..10 {
+            assert_eq!(
+                run(&mut state, &mut connection, &["RANDOMKEY"]),
+                b"$4\r\nlive\r\n"
+            );
+        }
+    }
+
+    #[test]
+    fn smembers_scard() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["SMEMBERS", "set"]),
+            b"*0\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SCARD", "set"]),
+            b":0\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SADD", "set", "a", "b", "c"]);
+        assert_eq!(
+            sorted_strings(&run(&mut state, &mut connection, &["SMEMBERS", "set"])),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SCARD", "set"]),
+            b":3\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        for command in ["SMEMBERS", "SCARD"] {
+            assert_eq!(
+                run(&mut state, &mut connection, &[command, "foo"]),
+                format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn sismember_smismember() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["SMISMEMBER", "set", "a", "b"]
+            ),
+            b"*2\r\n:0\r\n:0\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SADD", "set", "a", "c"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["SISMEMBER", "set", "a"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SISMEMBER", "set", "b"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["SMISMEMBER", "set", "c", "b", "a", "d"]
+            ),
+            b"*4\r\n:1\r\n:0\r\n:1\r\n:0\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        for command in ["SISMEMBER", "SMISMEMBER"] {
+            assert_eq!(
+                run(&mut state, &mut connection, &[command, "foo", "a"]),
+                format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn flushdb_flushall() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "0"]);
+        run(&mut state, &mut connection, &["SELECT", "1"]);
+        run(&mut state, &mut connection, &["SET", "foo", "1"]);
+        run(&mut state, &mut connection, &["SET", "bar", "1"]);
+        state.take_propagated();
+
+        assert_eq!(run(&mut state, &mut connection, &["FLUSHDB"]), b"+OK\r\n");
+        assert_eq!(state.databases[1].iter_live().count(), 0);
+        assert!(state.databases[0].get("foo").is_some());
+        let propagated = state.take_propagated();
+        assert!(matches!(&propagated[..], [Message::FlushDb]));
+
+        run(&mut state, &mut connection, &["SET", "foo", "1"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["FLUSHALL", "ASYNC"]),
+            b"+OK\r\n"
+        );
+        assert!(state.databases.iter().all(|db| db.iter_live().count() == 0));
+    }
+
+    #[test]
+    fn set_algebra() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+        run(
+            &mut state,
+            &mut connection,
+            &["SADD", "s1", "a", "b", "c", "d"],
+        );
+        run(&mut state, &mut connection, &["SADD", "s2", "b", "c", "e"]);
+        run(&mut state, &mut connection, &["SADD", "s3", "c", "d", "f"]);
+
+        let mut set_command =
+            |args: &[&str]| sorted_strings(&run(&mut state, &mut connection, args));
+        assert_eq!(set_command(&["SINTER", "s1", "s2", "s3"]), ["c"]);
+        assert_eq!(
+            set_command(&["SUNION", "s1", "s2", "s3"]),
+            ["a", "b", "c", "d", "e", "f"]
+        );
+        assert_eq!(set_command(&["SDIFF", "s1", "s2", "s3"]), ["a"]);
+
+        // Missing keys are empty sets
+        assert!(set_command(&["SINTER", "s1", "s2", "missing"]).is_empty());
+        assert_eq!(
+            set_command(&["SUNION", "s2", "missing", "s3"]),
+            ["b", "c", "d", "e", "f"]
+        );
+        assert_eq!(set_command(&["SDIFF", "s2", "missing", "s3"]), ["b", "e"]);
+        assert!(set_command(&["SDIFF", "missing", "s1", "s2"]).is_empty());
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        for command in ["SINTER", "SUNION", "SDIFF"] {
+            assert_eq!(
+                run(&mut state, &mut connection, &[command, "s1", "foo", "s2"]),
+                format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn zadd_gt_lt() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+        let score =
+            |state: &State, member: &str| match &state.databases[0].get("zset").unwrap().data {
+                StoreData::SortedSet(sorted_set) => sorted_set.get(member).copied(),
+                _ => panic!("expected sorted set"),
+            };
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZADD", "zset", "XX", "1", "a"]
+            ),
+            b":0\r\n"
+        );
+        assert!(state.databases[0].get("zset").is_none());
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZADD", "zset", "10", "a", "20", "b"]
+            ),
+            b":2\r\n"
+        );
+
+        // GT never lowers a score, but still adds new members
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZADD", "zset", "GT", "CH", "5", "a", "1", "c"]
+            ),
+            b":1\r\n"
+        );
+        assert_eq!(score(&state, "a"), Some(10.0));
+        assert_eq!(score(&state, "c"), Some(1.0));
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZADD", "zset", "GT", "CH", "15", "a"]
+            ),
+            b":1\r\n"
+        );
+        assert_eq!(score(&state, "a"), Some(15.0));
+
+        // LT never raises one
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZADD", "zset", "LT", "CH", "25", "b"]
+            ),
+            b":0\r\n"
+        );
+        assert_eq!(score(&state, "b"), Some(20.0));
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZADD", "zset", "LT", "5", "b"]
+            ),
+            b":0\r\n"
+        );
+        assert_eq!(score(&state, "b"), Some(5.0));
+
+        // NX only adds
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZADD", "zset", "NX", "CH", "0", "a", "0", "d"]
+            ),
+            b":1\r\n"
+        );
+        assert_eq!(score(&state, "a"), Some(15.0));
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZADD", "foo", "1", "a"]),
+            format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+        );
+    }
+
+    #[test]
+    fn spop_srandmember() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["SPOP", "set"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SRANDMEMBER", "set", "2"]),
+            b"*0\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SADD", "set", "a", "b", "c"]);
+        let members = ["a", "b", "c"];
+
+        // Positive counts are distinct members, negative counts may repeat them
+        let chosen = sorted_strings(&run(
+            &mut state,
+            &mut connection,
+            &["SRANDMEMBER", "set", "5"],
+        ));
+        assert_eq!(chosen, members);
+        let chosen = sorted_strings(&run(
+            &mut state,
+            &mut connection,
+            &["SRANDMEMBER", "set", "-5"],
+        ));
+        assert_eq!(chosen.len(), 5);
+        assert!(chosen.iter().all(|m| members.contains(&m.as_str())));
+        // Far more repeats than could ever be sent
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["SRANDMEMBER", "set", "-4611686018427387903"]
+            ),
+            b"-ERR value is out of range\r\n"
+        );
+
+        state.take_propagated();
+        let popped = sorted_strings(&run(&mut state, &mut connection, &["SPOP", "set", "2"]));
+        assert_eq!(popped.len(), 2);
+        popped.windows(2).for_each(|w| assert_ne!(w[0], w[1]));
+        let propagated = state.take_propagated();
+        let [Message::SRem {
+            members: removed, ..
+        }] = &propagated[..]
+        else {
+            panic!("expected SREM, got {:?}", propagated);
+        };
+        let mut removed = removed.clone();
+        removed.sort();
+        assert_eq!(removed, popped);
+
+        let remaining = members
+            .iter()
+            .find(|m| !popped.contains(&m.to_string()))
+            .unwrap();
+        assert_eq!(
+            run(&mut state, &mut connection, &["SPOP", "set"]),
+            format!("$1\r\n{}\r\n", remaining).as_bytes()
+        );
+        assert!(state.databases[0].get("set").is_none());
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        for command in ["SPOP", "SRANDMEMBER"] {
+            assert_eq!(
+                run(&mut state, &mut connection, &[command, "foo"]),
+                format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn info_sections() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        let info =
+            String::from_utf8(run(&mut state, &mut connection, &["INFO", "server"])).unwrap();
+        assert!(info.contains("#Server"));
+        assert!(info.contains("redis_version:7.2.0"));
+        assert!(!info.contains("#Replication"));
+
+        let info =
+            String::from_utf8(run(&mut state, &mut connection, &["INFO", "Replication"])).unwrap();
+        assert!(info.contains("#Replication"));
+        assert!(info.contains("role:master"));
+        assert!(!info.contains("#Server"));
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["INFO", "bogus"]),
+            b"$0\r\n\r\n"
+        );
+
+        let info = String::from_utf8(run(&mut state, &mut connection, &["INFO"])).unwrap();
+        for section in ["#Server", "#Replication", "#Keyspace"] {
+            assert!(info.contains(section));
+        }
+    }
+
+    #[test]
+    fn hgetall_hkeys_hvals() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        for command in ["HGETALL", "HKEYS", "HVALS"] {
+            assert_eq!(
+                run(&mut state, &mut connection, &[command, "hash"]),
+                b"*0\r\n"
+            );
+        }
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["HSET", "hash", "f1", "v1", "f2", "v2"]
+            ),
+            b":2\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["HSET", "hash", "f2", "v3", "f3", "v3"]
+            ),
+            b":1\r\n"
+        );
+
+        // Pairs stay together, in whatever order the fields come back
+        let output = run(&mut state, &mut connection, &["HGETALL", "hash"]);
+        let (RespValue::Array(elements), _) = RespValue::deserialize(&output).unwrap() else {
+            panic!("expected array");
+        };
+        let mut pairs = elements
+            .chunks(2)
+            .map(|pair| match pair {
+                [RespValue::BulkString(field), RespValue::BulkString(value)] => {
+                    (field.to_string(), value.to_string())
+                }
+                _ => panic!("expected field and value"),
+            })
+            .collect::<Vec<_>>();
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            [("f1", "v1"), ("f2", "v3"), ("f3", "v3")].map(|(f, v)| (f.to_string(), v.to_string()))
+        );
+        assert_eq!(
+            sorted_strings(&run(&mut state, &mut connection, &["HKEYS", "hash"])),
+            ["f1", "f2", "f3"]
+        );
+        assert_eq!(
+            sorted_strings(&run(&mut state, &mut connection, &["HVALS", "hash"])),
+            ["v1", "v3", "v3"]
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        for command in ["HGETALL", "HKEYS", "HVALS"] {
+            assert_eq!(
+                run(&mut state, &mut connection, &[command, "foo"]),
+                format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+            );
+        }
+        assert_eq!(
+            run(&mut state, &mut connection, &["HSET", "foo", "f", "v"]),
+            format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+        );
+    }
+
+    #[test]
+    fn hdel_hlen_hexists() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["HLEN", "hash"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["HEXISTS", "hash", "f1"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["HDEL", "hash", "f1"]),
+            b":0\r\n"
+        );
+
+        run(
+            &mut state,
+            &mut connection,
+            &["HSET", "hash", "f1", "v1", "f2", "v2"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["HLEN", "hash"]),
+            b":2\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["HEXISTS", "hash", "f1"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["HDEL", "hash", "f1", "f3"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["HEXISTS", "hash", "f1"]),
+            b":0\r\n"
+        );
+
+        // Removing the last field removes the key
+        assert_eq!(
+            run(&mut state, &mut connection, &["HDEL", "hash", "f2"]),
+            b":1\r\n"
+        );
+        assert!(state.databases[0].get("hash").is_none());
+        assert_eq!(
+            run(&mut state, &mut connection, &["EXISTS", "hash"]),
+            b":0\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        for args in [
+            &["HDEL", "foo", "f"][..],
+            &["HLEN", "foo"],
+            &["HEXISTS", "foo", "f"],
+        ] {
+            assert_eq!(
+                run(&mut state, &mut connection, args),
+                format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn hmget() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["HMGET", "hash", "f1", "f2"]),
+            b"*2\r\n$-1\r\n$-1\r\n"
+        );
+
+        run(
+            &mut state,
+            &mut connection,
+            &["HSET", "hash", "f1", "v1", "f3", "v3"],
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["HMGET", "hash", "f3", "f2", "f1"]
+            ),
+            b"*3\r\n$2\r\nv3\r\n$-1\r\n$2\r\nv1\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["HMGET", "foo", "f1"]),
+            format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+        );
+    }
+
+    #[test]
+    fn set_xx_get_wrong_type() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+        run(&mut state, &mut connection, &["RPUSH", "list", "a", "b"]);
+        state.take_propagated();
+
+        // The type check for GET comes before the value is replaced
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["SET", "list", "v", "XX", "GET"]
+            ),
+            format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+        );
+        assert_eq!(
+            state.databases[0].get("list").unwrap().data,
+            StoreData::List(["a", "b"].map(String::from).into())
+        );
+        assert!(state.take_propagated().is_empty());
+
+        // Without GET, SET replaces the list
+        assert_eq!(
+            run(&mut state, &mut connection, &["SET", "list", "v", "XX"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            state.databases[0].get("list").unwrap().data,
+            StoreData::String("v".to_string())
+        );
+    }
+
+    #[test]
+    fn latency() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        // Disabled by default
+        run(&mut state, &mut connection, &["DEBUG", "SLEEP", "0.05"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["LATENCY", "LATEST"]),
+            b"*0\r\n"
+        );
+
+        run(
+            &mut state,
+            &mut connection,
+            &["CONFIG", "SET", "latency-monitor-threshold", "50"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["DEBUG", "SLEEP", "0.1"]),
+            b"+OK\r\n"
+        );
+        run(&mut state, &mut connection, &["PING"]);
+
+        let output = run(&mut state, &mut connection, &["LATENCY", "LATEST"]);
+        let (RespValue::Array(events), _) = RespValue::deserialize(&output).unwrap() else {
+            panic!("expected array");
+        };
+        let [RespValue::Array(event)] = &events[..] else {
+            panic!("expected one event, got {:?}", events);
+        };
+        let [RespValue::BulkString("command"), RespValue::Integer(_), RespValue::Integer(latest), RespValue::Integer(max)] =
+            &event[..]
+        else {
+            panic!("unexpected event {:?}", event);
+        };
+        assert!((100..200).contains(latest));
+        assert_eq!(latest, max);
+
+        let output = run(
+            &mut state,
+            &mut connection,
+            &["LATENCY", "HISTORY", "command"],
+        );
+        assert!(output.starts_with(b"*1\r\n*2\r\n"));
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["LATENCY", "RESET"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["LATENCY", "HISTORY", "command"]
+            ),
+            b"*0\r\n"
+        );
+    }
+
+    #[test]
+    fn zrange() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZRANGE", "zset", "0", "-1"]),
+            b"*0\r\n"
+        );
+
+        // Ties are broken lexicographically
+        run(
+            &mut state,
+            &mut connection,
+            &["ZADD", "zset", "2", "c", "1", "b", "2", "a", "-1.5", "d"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZRANGE", "zset", "0", "-1"]),
+            b"*4\r\n$1\r\nd\r\n$1\r\nb\r\n$1\r\na\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZRANGE", "zset", "-3", "1", "WITHSCORES"]
+            ),
+            b"*2\r\n$1\r\nb\r\n$1\r\n1\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZRANGE", "zset", "0", "0", "withscores"]
+            ),
+            b"*2\r\n$1\r\nd\r\n$4\r\n-1.5\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZRANGE", "zset", "2", "100"]),
+            b"*2\r\n$1\r\na\r\n$1\r\nc\r\n"
+        );
+        for (start, stop) in [("3", "1"), ("4", "10"), ("-100", "-10")] {
+            assert_eq!(
+                run(
+                    &mut state,
+                    &mut connection,
+                    &["ZRANGE", "zset", start, stop]
+                ),
+                b"*0\r\n"
+            );
+        }
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZRANGE", "foo", "0", "-1"]),
+            format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+        );
+    }
+
+    #[test]
+    fn slowlog() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(
+            &mut state,
+            &mut connection,
+            &["CONFIG", "SET", "slowlog-log-slower-than", "50000"],
+        );
+        run(&mut state, &mut connection, &["DEBUG", "SLEEP", "0.1"]);
+        run(&mut state, &mut connection, &["PING"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["SLOWLOG", "LEN"]),
+            b":1\r\n"
+        );
+
+        let output = run(&mut state, &mut connection, &["SLOWLOG", "GET"]);
+        let (RespValue::Array(entries), _) = RespValue::deserialize(&output).unwrap() else {
+            panic!("expected array");
+        };
+        let [RespValue::Array(entry)] = &entries[..] else {
+            panic!("expected one entry");
+        };
+        assert_eq!(entry[0], RespValue::Integer(0));
+        assert!(matches!(entry[2], RespValue::Integer(micros) if micros >= 100_000));
+        assert_eq!(
+            entry[3],
+            RespValue::Array(vec![
+                RespValue::BulkString("DEBUG"),
+                RespValue::BulkString("SLEEP"),
+                RespValue::BulkString("0.1"),
+            ])
+        );
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["SLOWLOG", "RESET"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SLOWLOG", "LEN"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SLOWLOG", "GET"]),
+            b"*0\r\n"
+        );
+
+        // Entries hold the arguments as the client sent them, not as re-serialized
+        run(
+            &mut state,
+            &mut connection,
+            &["CONFIG", "SET", "slowlog-log-slower-than", "0"],
+        );
+        run(&mut state, &mut connection, &["set", "k", "v", "EX", "10"]);
+        let output = run(&mut state, &mut connection, &["SLOWLOG", "GET", "1"]);
+        let (RespValue::Array(entries), _) = RespValue::deserialize(&output).unwrap() else {
+            panic!("expected array");
+        };
+        let [RespValue::Array(entry)] = &entries[..] else {
+            panic!("expected one entry");
+        };
+        assert_eq!(
+            entry[3],
+            RespValue::Array(vec![
+                RespValue::BulkString("set"),
+                RespValue::BulkString("k"),
+                RespValue::BulkString("v"),
+                RespValue::BulkString("EX"),
+                RespValue::BulkString("10"),
+            ])
+        );
+    }
+
+    #[test]
+    fn zrank_zrevrank() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(
+            &mut state,
+            &mut connection,
+            &["ZADD", "zset", "2", "b", "1", "a", "2", "c", "3", "d"],
+        );
+        for (member, rank, rev_rank) in [("a", 0, 3), ("b", 1, 2), ("c", 2, 1), ("d", 3, 0)] {
+            assert_eq!(
+                run(&mut state, &mut connection, &["ZRANK", "zset", member]),
+                format!(":{rank}\r\n").as_bytes()
+            );
+            assert_eq!(
+                run(&mut state, &mut connection, &["ZREVRANK", "zset", member]),
+                format!(":{rev_rank}\r\n").as_bytes()
+            );
+        }
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZRANK", "zset", "e"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZREVRANK", "missing", "a"]),
+            b"$-1\r\n"
+        );
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZRANK", "foo", "a"]),
+            format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+        );
+    }
+
+    #[test]
+    fn monitor() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut monitor = client();
+        let mut connection = client();
+        connection.addr = Some("127.0.0.1:60866".parse().unwrap());
+
+        assert_eq!(run(&mut state, &mut monitor, &["MONITOR"]), b"+OK\r\n");
+        assert!(state.next_outgoing(&mut monitor).unwrap().is_none());
+
+        run(&mut state, &mut connection, &["SET", "foo", "a \"b\"\n"]);
+        let Some(Message::Reply(RespValue::OwnedSimpleString(line))) =
+            state.next_outgoing(&mut monitor).unwrap()
+        else {
+            panic!("expected a monitor line");
+        };
+        let (timestamp, rest) = line.split_once(' ').unwrap();
+        assert!(timestamp.parse::<f64>().is_ok());
+        assert_eq!(rest, r#"[0 127.0.0.1:60866] "SET" "foo" "a \"b\"\n""#);
+        assert!(state.next_outgoing(&mut monitor).unwrap().is_none());
+
+        // The arguments are shown as the client sent them, not as re-serialized
+        run(&mut state, &mut connection, &["set", "k", "v", "ex", "10"]);
+        let Some(Message::Reply(RespValue::OwnedSimpleString(line))) =
+            state.next_outgoing(&mut monitor).unwrap()
+        else {
+            panic!("expected a monitor line");
+        };
+        assert!(line.ends_with(r#"] "set" "k" "v" "ex" "10""#));
+
+        // Nothing is queued once the monitor goes away
+        state.remove_monitor(monitor.monitor_id.unwrap());
+        run(&mut state, &mut connection, &["GET", "foo"]);
+        assert!(state.monitors.is_empty());
+    }
+
+    #[test]
+    fn zrem_zcard_zincrby() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZINCRBY", "zset", "2.5", "a"]
+            ),
+            b"$3\r\n2.5\r\n"
+        );
+        run(
+            &mut state,
+            &mut connection,
+            &["ZADD", "zset", "1", "b", "3", "c"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZINCRBY", "zset", "-2", "c"]),
+            b"$1\r\n1\r\n"
+        );
+        // The order follows the new scores, with ties broken by member
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZRANGE", "zset", "0", "-1"]),
+            b"*3\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\na\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZCARD", "zset"]),
+            b":3\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZCARD", "missing"]),
+            b":0\r\n"
+        );
+        state.take_propagated();
+
+        assert_eq!(
+            run(
+                &mut state,
+                &mut connection,
+                &["ZREM", "zset", "a", "b", "d"]
+            ),
+            b":2\r\n"
+        );
+        assert_eq!(state.take_propagated().len(), 1);
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZREM", "zset", "d"]),
+            b":0\r\n"
+        );
+        assert!(state.take_propagated().is_empty());
+        assert_eq!(
+            run(&mut state, &mut connection, &["ZREM", "zset", "c"]),
+            b":1\r\n"
+        );
+        assert!(state.databases[0].get("zset").is_none());
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        for args in [
+            &["ZREM", "foo", "a"][..],
+            &["ZCARD", "foo"],
+            &["ZINCRBY", "foo", "1", "a"],
+        ] {
+            assert_eq!(
+                run(&mut state, &mut connection, args),
+                format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn debug_reload_save_error() {
+        let dir = std::env::temp_dir()
+            .join("redis-missing-dir")
+            .join("nested");
+        let config = Config(HashMap::from([
+            (ConfigKey::Dir, vec![dir.to_str().unwrap().to_string()]),
+            (ConfigKey::DbFilename, vec!["dump.rdb".to_string()]),
+        ]));
+        let mut state = State::new(config).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+        let output = run(&mut state, &mut connection, &["DEBUG", "RELOAD"]);
+        assert!(output.starts_with(b"-ERR Error trying to save the DB: "));
+        assert!(output.ends_with(b"\r\n"));
+        // Nothing was lost
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "foo"]),
+            b"$3\r\nbar\r\n"
+        );
+    }
+
+    #[test]
+    fn debug_reload_keeps_encodings() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        let long = "x".repeat(65);
+        let many = (0..600).map(|i| i.to_string()).collect::<Vec<_>>();
+        let mut commands = vec![
+            vec!["SET", "int", "12"],
+            vec!["SET", "embstr", "hello"],
+            vec!["SET", "raw", &long],
+            vec!["RPUSH", "listpack_list", "a", "1"],
+            vec!["RPUSH", "quicklist", "a", &long],
+            vec!["SADD", "intset", "1", "2", "3"],
+            vec!["SADD", "listpack_set", "a", "1"],
+            vec!["SADD", "hashtable_set", "a", &long],
+            vec!["HSET", "listpack_hash", "field", "value"],
+            vec!["HSET", "hashtable_hash", "field", &long],
+            vec!["ZADD", "listpack_zset", "1", "a", "2", "b"],
+            vec!["ZADD", "skiplist", "1", &long],
+        ];
+        let mut big_intset = vec!["SADD", "big_intset"];
+        big_intset.extend(many.iter().map(String::as_str));
+        commands.push(big_intset);
+        for command in &commands {
+            run(&mut state, &mut connection, command);
+        }
+
+        let encodings = |state: &mut State, connection: &mut Connection| {
+            commands
+                .iter()
+                .map(|command| run(state, connection, &["OBJECT", "ENCODING", command[1]]))
+                .collect::<Vec<_>>()
+        };
+        let before = encodings(&mut state, &mut connection);
+        assert_eq!(before[0], b"$3\r\nint\r\n");
+        assert_eq!(before[5], b"$6\r\nintset\r\n");
+        assert_eq!(before[12], b"$9\r\nhashtable\r\n");
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["DEBUG", "RELOAD"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(encodings(&mut state, &mut connection), before);
+    }
+
+    #[test]
+    fn zadd_wrong_type() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(&mut state, &mut connection, &["SADD", "setkey", "m", "n"]);
+        run(&mut state, &mut connection, &["SET", "string", "value"]);
+        state.take_propagated();
+
+        for key in ["setkey", "string"] {
+            assert_eq!(
+                run(&mut state, &mut connection, &["ZADD", key, "1", "m"]),
+                format!("-{}\r\n", super::WRONGTYPE).as_bytes()
+            );
+        }
+        assert_eq!(
+            state.databases[0].get("setkey").unwrap().data,
+            StoreData::Set(["m", "n"].map(String::from).into())
+        );
+        assert_eq!(
+            state.databases[0].get("string").unwrap().data,
+            StoreData::String("value".to_string())
+        );
+        assert!(state.take_propagated().is_empty());
+    }
+
+    #[test]
+    fn null_by_protocol() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "missing"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["HMGET", "missing", "a"]),
+            b"*1\r\n$-1\r\n"
+        );
+
+        connection.protocol = Protocol::Resp3;
+        assert_eq!(
+            run(&mut state, &mut connection, &["GET", "missing"]),
+            b"_\r\n"
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["HMGET", "missing", "a"]),
+            b"*1\r\n_\r\n"
+        );
+    }
+
+    #[test]
+    fn hello() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        // Under RESP2, the map comes back as a flat array
+        let output = run(&mut state, &mut connection, &["HELLO"]);
+        assert!(output.starts_with(b"*12\r\n$6\r\nserver\r\n$5\r\nredis\r\n"));
+        assert!(output
+            .windows(b"$5\r\nproto\r\n:2\r\n".len())
+            .any(|w| w == b"$5\r\nproto\r\n:2\r\n"));
+
+        let output = run(&mut state, &mut connection, &["HELLO", "3"]);
+        assert_eq!(connection.protocol, Protocol::Resp3);
+        let (RespValue::Map(entries), _) = RespValue::deserialize(&output).unwrap() else {
+            panic!("expected map");
+        };
+        assert!(entries.contains(&(RespValue::BulkString("proto"), RespValue::Integer(3))));
+        assert!(entries.contains(&(
+            RespValue::BulkString("role"),
+            RespValue::BulkString("master")
+        )));
+
+        assert_eq!(
+            run(&mut state, &mut connection, &["HELLO", "4"]),
+            b"-NOPROTO unsupported protocol version\r\n"
+        );
+        assert_eq!(connection.protocol, Protocol::Resp3);
+
+        run(&mut state, &mut connection, &["HELLO", "2"]);
+        assert_eq!(connection.protocol, Protocol::Resp2);
+    }
+
+    #[test]
+    fn concurrent_get_and_expire() {
+        let state = std::sync::Mutex::new(State::new(Config::default()).unwrap());
+        let deadline = unix_time_millis() + 50;
+        {
+            let mut state = state.lock().unwrap();
+            let mut connection = client();
+            run(&mut state, &mut connection, &["SET", "foo", "bar"]);
+            let deadline = deadline.to_string();
+            run(
+                &mut state,
+                &mut connection,
+                &["PEXPIREAT", "foo", &deadline],
+            );
+        }
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut connection = client();
+                    while unix_time_millis() < deadline + 20 {
+                        let before = unix_time_millis();
+                        let output =
+                            run(&mut state.lock().unwrap(), &mut connection, &["GET", "foo"]);
+                        if output != b"$-1\r\n" {
+                            assert_eq!(output, b"$3\r\nbar\r\n");
+                            assert!(before <= deadline, "read foo after it expired");
+                        }
+                    }
+                });
+            }
+            scope.spawn(|| {
+                let mut connection = client();
+                let deadline = deadline.to_string();
+                while unix_time_millis() < deadline.parse::<u64>().unwrap() + 20 {
+                    run(
+                        &mut state.lock().unwrap(),
+                        &mut connection,
+                        &["PEXPIREAT", "foo", &deadline],
+                    );
+                }
+            });
+        });
+
+        // Whichever command touched the key first after the deadline deleted it, and
+        // told replicas
+        let mut state = state.into_inner().unwrap();
+        // `scan` still sees expired keys that haven't been deleted
+        assert!(state.databases[0].scan(0, 10).1.is_empty());
+        assert!(state
+            .take_propagated()
+            .iter()
+            .any(|m| matches!(m, Message::Del { keys } if keys == &["foo"])));
+    }
+
+    #[test]
+    fn proto_max_bulk_len() {
+        let mut state = State::new(Config::default()).unwrap();
+        let mut connection = client();
+
+        run(
+            &mut state,
+            &mut connection,
+            &["CONFIG", "SET", "proto-max-bulk-len", "10"],
+        );
+        assert_eq!(
+            run(&mut state, &mut connection, &["SET", "foo", "0123456789"]),
+            b"+OK\r\n"
+        );
+        state.take_propagated();
+        assert_eq!(
+            run(&mut state, &mut connection, &["SET", "foo", "0123456789a"]),
+            b"-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n"
+        );
+        assert_eq!(
+            state.databases[0].get("foo").unwrap().data,
+            StoreData::String("0123456789".to_string())
+        );
+        assert!(state.take_propagated().is_empty());
+    }
+
+    #[test]
+    fn replica_offset_from_fullresync() {
+        let mut config = Config::default();
+        config.0.insert(
+            ConfigKey::ReplicaOf,
+            vec!["localhost".to_string(), "6379".to_string()],
+        );
+        config.0.insert(ConfigKey::Port, vec!["6380".to_string()]);
+        let mut replica = State::new(config).unwrap();
+        let mut master_connection = client();
+        master_connection.ty = ConnectionType::Master;
+
+        // PING, REPLCONF twice, then PSYNC, each answered by the master
+        for reply in [
+            Message::Pong,
+            Message::Ok,
+            Message::Ok,
+            Message::FullResync {
+                replication_id: super::REPLICATION_ID.to_string(),
+                offset: 1234,
+            },
+        ] {
+            assert!(replica
+                .next_outgoing(&mut master_connection)
+                .unwrap()
+                .is_some());
+            replica
+                .handle_incoming(&reply, &[], &mut master_connection)
+                .unwrap();
+        }
+
+        let info =
+            String::from_utf8(run(&mut replica, &mut client(), &["INFO", "replication"])).unwrap();
+        assert!(info.contains("slave_repl_offset:1234"));
+
+        replica.increment_offset(37);
+        let getack = Message::ReplicationConfig {
+            key: "GETACK".to_string(),
+            value: "*".to_string(),
+        };
+        assert!(matches!(
+            replica.handle_incoming(&getack, &[], &mut master_connection).unwrap(),
+            Some(Message::ReplicationConfig { key, value }) if key == "ACK" && value == "1271"
+        ));
+    }
+}
@@ -1,22 +1,441 @@
 use std::{
-    collections::HashMap,
-    time::{Duration, Instant},
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// A single database. Expired keys are never returned, even while they're still
+/// waiting to be deleted.
 #[derive(Default)]
 pub struct Store {
-    pub data: HashMap<String, StoreValue>,
+    data: HashMap<String, StoreValue>,
+    /// Every key, ordered by its `SCAN` cursor, so a scan can pick up where the
+    /// last batch left off without sorting the whole keyspace again.
+    cursor_index: BTreeSet<(u64, String)>,
 }
 
 #[derive(Debug)]
 pub struct StoreValue {
-    pub data: String,
+    pub data: StoreData,
+    /// When the value was last written. Relative expiries count from here, so reads
+    /// must never move it.
     pub updated: Instant,
+    /// When the value was last accessed, for idle-time tracking. Unlike `updated`,
+    /// this is bumped by reads like `TOUCH`.
+    pub accessed: Instant,
     pub expiry: Option<StoreExpiry>,
 }
 
-#[derive(Debug)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreData {
+    String(String),
+    List(VecDeque<String>),
+    Set(HashSet<String>),
+    Hash(HashMap<String, String>),
+    /// Members and their scores. Ordering is worked out when it's needed.
+    SortedSet(HashMap<String, f64>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StoreExpiry {
     Duration(Duration),
     UnixTimestampMillis(u64),
 }
+
+impl Store {
+    /// The value at `key`, if it exists and hasn't expired.
+    pub fn get(&self, key: &str) -> Option<&StoreValue> {
+        self.data.get(key).filter(|v| !v.is_expired())
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut StoreValue> {
+        self.data.get_mut(key).filter(|v| !v.is_expired())
+    }
+
+    /// Like `get_mut`, but an expired key is deleted rather than left for later.
+    pub fn lazy_get(&mut self, key: &str) -> Option<&mut StoreValue> {
+        self.remove_expired(key);
+        self.data.get_mut(key)
+    }
+
+    /// The value at `key`, setting it to a new value with no expiry first if it
+    /// doesn't exist.
+    pub fn get_or_set_with(
+        &mut self,
+        key: &str,
+        data: impl FnOnce() -> StoreData,
+    ) -> &mut StoreValue {
+        if self.lazy_get(key).is_none() {
+            self.set(key.to_string(), data());
+        }
+        self.data.get_mut(key).unwrap()
+    }
+
+    /// Set `key` to a new value with no expiry, replacing any existing value.
+    pub fn set(&mut self, key: String, data: StoreData) {
+        self.set_with_expiry(key, data, None);
+    }
+
+    /// Set `key` to a new value, replacing any existing value. A relative expiry
+    /// counts from now.
+    pub fn set_with_expiry(&mut self, key: String, data: StoreData, expiry: Option<StoreExpiry>) {
+        if !self.data.contains_key(&key) {
+            self.cursor_index.insert((key_hash(&key), key.clone()));
+        }
+        self.data.insert(key, StoreValue::new(data, expiry));
+    }
+
+    /// Remove `key`, returning its value if it hadn't expired.
+    pub fn remove(&mut self, key: &str) -> Option<StoreValue> {
+        let value = self.data.remove(key)?;
+        self.cursor_index.remove(&(key_hash(key), key.to_string()));
+        Some(value).filter(|v| !v.is_expired())
+    }
+
+    /// Delete `key` if it has expired, returning whether it did.
+    pub fn remove_expired(&mut self, key: &str) -> bool {
+        if self.data.get(key).is_some_and(|v| v.is_expired()) {
+            self.remove(key);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Roughly `count` keys from `cursor` onwards, including expired ones so the caller
+    /// can delete them, and the cursor to continue from (0 once every key has been
+    /// visited). Keys are visited in order of their hash, so a key that exists for the
+    /// whole scan is returned exactly once, however the store changes in between.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<String>) {
+        let mut keys = Vec::new();
+        let mut last_hash = None;
+        for (hash, key) in self.cursor_index.range((cursor, String::new())..) {
+            // Keys sharing a hash can't be split across batches, as the cursor
+            // couldn't tell them apart
+            if keys.len() >= count.max(1) && last_hash != Some(*hash) {
+                return (*hash, keys);
+            }
+            last_hash = Some(*hash);
+            keys.push(key.clone());
+        }
+        (0, keys)
+    }
+
+    /// Every key that has expired but hasn't been deleted yet.
+    pub fn expired_keys(&self) -> Vec<String> {
+        self.data
+            .iter()
+            .filter(|(_, v)| v.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Every key that hasn't expired, in no particular order.
+    pub fn iter_live(&self) -> impl Iterator<Item = (&String, &StoreValue)> {
+        self.data.iter().filter(|(_, v)| !v.is_expired())
+    }
+
+    /// Delete every key.
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.cursor_index.clear();
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.data.reserve(additional);
+    }
+
+    /// The `INFO` keyspace summary for this database, or `None` if it has no live keys.
+    pub fn keyspace_info(&self) -> Option<String> {
+        let (keys, expires) = self.iter_live().fold((0, 0), |(keys, expires), (_, v)| {
+            (keys + 1, expires + usize::from(v.expiry.is_some()))
+        });
+        (keys > 0).then(|| format!("keys={keys},expires={expires},avg_ttl=0"))
+    }
+}
+
+impl StoreValue {
+    pub fn new(data: StoreData, expiry: Option<StoreExpiry>) -> StoreValue {
+        let now = Instant::now();
+        StoreValue {
+            data,
+            updated: now,
+            accessed: now,
+            expiry,
+        }
+    }
+
+    /// The name of this value's type, as reported by `TYPE`.
+    pub fn type_name(&self) -> &'static str {
+        match self.data {
+            StoreData::String(_) => "string",
+            StoreData::List(_) => "list",
+            StoreData::Set(_) => "set",
+            StoreData::Hash(_) => "hash",
+            StoreData::SortedSet(_) => "zset",
+        }
+    }
+
+    /// The internal encoding Redis would use for this value, as reported by
+    /// `OBJECT ENCODING`. Short strings are embedded in the object header, and small
+    /// collections of short elements are packed into a single allocation.
+    pub fn encoding(&self) -> &'static str {
+        match &self.data {
+            StoreData::String(s) if is_integer(s) => "int",
+            StoreData::String(s) if s.len() <= 44 => "embstr",
+            StoreData::String(_) => "raw",
+            StoreData::List(list) if packable(list.len(), list.iter()) => "listpack",
+            StoreData::List(_) => "quicklist",
+            StoreData::Set(set) if set.len() <= 512 && set.iter().all(|m| is_integer(m)) => {
+                "intset"
+            }
+            StoreData::Set(set) if packable(set.len(), set.iter()) => "listpack",
+            StoreData::Set(_) => "hashtable",
+            StoreData::Hash(hash)
+                if packable(hash.len(), hash.iter().flat_map(|(k, v)| [k, v])) =>
+            {
+                "listpack"
+            }
+            StoreData::Hash(_) => "hashtable",
+            StoreData::SortedSet(zset) if packable(zset.len(), zset.keys()) => "listpack",
+            StoreData::SortedSet(_) => "skiplist",
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expiry {
+            Some(StoreExpiry::Duration(d)) => self
+                .updated
+                .checked_add(d)
+                .is_some_and(|t| Instant::now() > t),
+            Some(StoreExpiry::UnixTimestampMillis(t)) => t < unix_time_millis(),
+            None => false,
+        }
+    }
+
+    /// Milliseconds until this value expires, or `None` if it has no expiry.
+    pub fn ttl_millis(&self) -> Option<i64> {
+        match self.expiry {
+            Some(StoreExpiry::Duration(d)) => Some(match self.updated.checked_add(d) {
+                Some(t) => i64::try_from(t.saturating_duration_since(Instant::now()).as_millis())
+                    .unwrap_or(i64::MAX),
+                None => i64::MAX,
+            }),
+            Some(StoreExpiry::UnixTimestampMillis(t)) => {
+                Some((t as i64).saturating_sub(unix_time_millis() as i64).max(0))
+            }
+            None => None,
+        }
+    }
+
+    /// The expiry of this value re-expressed relative to `now`, so it can be carried
+    /// over to a value with a fresh `updated` time.
+    pub fn expiry_from(&self, now: Instant) -> Option<StoreExpiry> {
+        match self.expiry {
+            Some(StoreExpiry::Duration(d)) => Some(StoreExpiry::Duration(
+                d.saturating_add(self.updated.saturating_duration_since(now))
+                    .saturating_sub(now.saturating_duration_since(self.updated)),
+            )),
+            expiry => expiry,
+        }
+    }
+
+    /// Replace the expiry of an existing value. Durations count from now, so they're
+    /// stored as an absolute timestamp rather than relative to `updated`.
+    pub fn set_expiry(&mut self, expiry: Option<StoreExpiry>) {
+        self.expiry = expiry.map(StoreExpiry::absolute);
+    }
+}
+
+impl StoreExpiry {
+    /// This expiry as an absolute timestamp, with durations counting from now.
+    pub fn absolute(self) -> StoreExpiry {
+        match self {
+            StoreExpiry::Duration(d) => StoreExpiry::UnixTimestampMillis(
+                unix_time_millis().saturating_add(u64::try_from(d.as_millis()).unwrap_or(u64::MAX)),
+            ),
+            expiry => expiry,
+        }
+    }
+}
+
+/// Whether a collection is small enough, with short enough elements, to be stored
+/// as a listpack.
+fn packable<'a>(len: usize, mut elements: impl Iterator<Item = &'a String>) -> bool {
+    len <= 128 && elements.all(|e| e.len() <= 64)
+}
+
+/// Whether `s` can be stored as an integer and turned back into exactly the same
+/// string, so "+5" and "007" don't count.
+fn is_integer(s: &str) -> bool {
+    s.parse::<i64>().is_ok_and(|n| n.to_string() == s)
+}
+
+/// The members of a sorted set in order, by score and then lexicographically.
+pub fn sorted_by_score(sorted_set: &HashMap<String, f64>) -> Vec<(&String, f64)> {
+    let mut members = sorted_set
+        .iter()
+        .map(|(member, score)| (member, *score))
+        .collect::<Vec<_>>();
+    members.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+    members
+}
+
+/// A hash of `key` that's the same on every run, for use as a `SCAN` cursor.
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Store, StoreData, StoreExpiry};
+    use std::time::Duration;
+
+    fn string(s: &str) -> StoreData {
+        StoreData::String(s.to_string())
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let mut store = Store::default();
+        assert!(store.get("foo").is_none());
+
+        store.set("foo".to_string(), string("bar"));
+        assert_eq!(store.get("foo").unwrap().data, string("bar"));
+        assert_eq!(store.get("foo").unwrap().expiry, None);
+
+        store.set("foo".to_string(), string("baz"));
+        assert_eq!(store.get("foo").unwrap().data, string("baz"));
+
+        assert_eq!(store.remove("foo").unwrap().data, string("baz"));
+        assert!(store.get("foo").is_none());
+        assert!(store.remove("foo").is_none());
+    }
+
+    #[test]
+    fn expired_values_are_hidden() {
+        let mut store = Store::default();
+        store.set_with_expiry(
+            "short".to_string(),
+            string("a"),
+            Some(StoreExpiry::Duration(Duration::from_millis(1))),
+        );
+        store.set_with_expiry(
+            "long".to_string(),
+            string("b"),
+            Some(StoreExpiry::Duration(Duration::from_secs(100))),
+        );
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert!(store.get("short").is_none());
+        assert!(store.get_mut("short").is_none());
+        assert!(store.get("long").is_some());
+        let live = store
+            .iter_live()
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(live, ["long"]);
+
+        // Removing an expired key doesn't hand back its value
+        assert!(store.remove("short").is_none());
+    }
+
+    #[test]
+    fn huge_expiry() {
+        let mut store = Store::default();
+        store.set_with_expiry(
+            "forever".to_string(),
+            string("a"),
+            Some(StoreExpiry::Duration(Duration::from_secs(i64::MAX as u64))),
+        );
+        let value = store.get("forever").unwrap();
+        assert_eq!(value.ttl_millis(), Some(i64::MAX));
+        assert_eq!(
+            value.expiry.unwrap().absolute(),
+            StoreExpiry::UnixTimestampMillis(u64::MAX)
+        );
+        assert_eq!(store.iter_live().count(), 1);
+    }
+
+    #[test]
+    fn lazy_get() {
+        let mut store = Store::default();
+        store.set_with_expiry(
+            "foo".to_string(),
+            string("bar"),
+            Some(StoreExpiry::Duration(Duration::from_millis(1))),
+        );
+        assert!(store.lazy_get("foo").is_some());
+        std::thread::sleep(Duration::from_millis(5));
+
+        // A plain get leaves the expired key in place, a lazy get deletes it
+        assert!(store.get("foo").is_none());
+        assert!(store.data.contains_key("foo"));
+        assert!(store.lazy_get("foo").is_none());
+        assert!(!store.data.contains_key("foo"));
+        assert!(!store.remove_expired("foo"));
+    }
+
+    #[test]
+    fn get_or_set_with() {
+        let mut store = Store::default();
+        store.set_with_expiry(
+            "foo".to_string(),
+            string("stale"),
+            Some(StoreExpiry::Duration(Duration::from_millis(1))),
+        );
+        std::thread::sleep(Duration::from_millis(5));
+
+        // An expired value is replaced rather than reused
+        let value = store.get_or_set_with("foo", || string("fresh"));
+        assert_eq!(value.data, string("fresh"));
+        assert_eq!(value.expiry, None);
+
+        let value = store.get_or_set_with("foo", || string("unused"));
+        assert_eq!(value.data, string("fresh"));
+    }
+
+    #[test]
+    fn scan() {
+        let mut store = Store::default();
+        for i in 0..20 {
+            store.set(format!("key:{i}"), string("x"));
+        }
+
+        // Keys deleted partway through don't stop the rest from being visited once
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, 3);
+            assert!(!keys.is_empty());
+            seen.extend(keys);
+            store.remove("key:19");
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        seen.sort();
+        seen.dedup();
+        assert!(seen.len() == 19 || seen.len() == 20);
+        assert!((0..19).all(|i| seen.contains(&format!("key:{i}"))));
+
+        assert_eq!(store.scan(0, 100).1.len(), 19);
+
+        // Overwriting a key doesn't visit it twice, and cleared keys are gone
+        store.set("key:0".to_string(), string("y"));
+        assert_eq!(store.scan(0, 100).1.len(), 19);
+        store.clear();
+        assert_eq!(store.scan(0, 100), (0, Vec::new()));
+    }
+}